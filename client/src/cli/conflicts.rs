@@ -0,0 +1,35 @@
+use crate::cli::output::OutputFormat;
+use crate::command::protocol::CommandResult;
+use crate::command::Command;
+use crate::{CommandWriter, Handler, Result};
+use anyhow::anyhow;
+use clap::Args;
+use prettytable::{format, row, Table};
+
+/// List the conflicts detected between local changes and remote versions
+#[derive(Debug, Args)]
+pub struct ConflictsCommand;
+
+impl Handler for ConflictsCommand {
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        let conflicts = match command_bus.send(Command::ListConflicts)? {
+            CommandResult::Conflicts(conflicts) => conflicts,
+            result => return Err(anyhow!("unexpected response. result={:?}", result)),
+        };
+
+        output.print(&conflicts, |conflicts| {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_CLEAN);
+            table.add_row(row!["PATH", "CONFLICT COPY", "VERSION", "DETECTED"]);
+            for conflict in conflicts {
+                table.add_row(row![
+                    conflict.path,
+                    conflict.copy,
+                    conflict.version,
+                    conflict.detected.format("%Y-%m-%d %H:%M:%S")
+                ]);
+            }
+            vec![table]
+        })
+    }
+}
@@ -0,0 +1,119 @@
+use crate::cli::absolute;
+use crate::cli::output::OutputFormat;
+use crate::command::pipe::CommandWriter;
+use crate::command::protocol::{CommandResult, DownloadRecord};
+use crate::command::Command;
+use crate::status::{format_bytes, Direction};
+use crate::{Handler, Result};
+use anyhow::anyhow;
+use clap::Args;
+use prettytable::{format, row, Table};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Interval between two reports of the progress of a download.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Download a file, in its local copy or anywhere else
+#[derive(Debug, Args)]
+pub struct GetCommand {
+    /// The remote path of the file, e.g `docs/report.pdf`
+    path: String,
+
+    /// The version to download, the latest one by default
+    #[clap(long)]
+    version: Option<i32>,
+
+    /// Where to download the file, a file or a directory. The file is downloaded in its local copy by default
+    #[clap(long)]
+    to: Option<String>,
+}
+
+impl Handler for GetCommand {
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        let to = self.to.as_deref().map(absolute).transpose()?;
+
+        // Only humans are shown the progress
+        let done = Arc::new(AtomicBool::new(false));
+        let progress = match output {
+            OutputFormat::Table => {
+                let (socket, path, done) = (
+                    command_bus.socket().to_string(),
+                    self.path.clone(),
+                    done.clone(),
+                );
+                Some(thread::spawn(move || {
+                    report_progress(&socket, &path, &done)
+                }))
+            }
+            _ => None,
+        };
+
+        let result = command_bus.send(Command::Get {
+            path: self.path.clone(),
+            version: self.version,
+            to,
+        });
+        done.store(true, Ordering::Relaxed);
+        if let Some(progress) = progress {
+            let _ = progress.join();
+        }
+
+        let download = match result? {
+            CommandResult::Download(download) => download,
+            result => return Err(anyhow!("unexpected response. result={:?}", result)),
+        };
+        output.print(&download, |download: &DownloadRecord| {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_CLEAN);
+            table.add_row(row!["PATH", "VERSION", "LOCAL", "SIZE", "HASH"]);
+            table.add_row(row![
+                download.path,
+                download.version,
+                download.local,
+                format_bytes(download.size),
+                download.hash
+            ]);
+            vec![table]
+        })
+    }
+}
+
+/// Print the progress of the download of the file at the remote `path`, as reported by the
+/// daemon listening on `socket`, until `done` is set.
+fn report_progress(socket: &str, path: &str, done: &AtomicBool) {
+    let mut reported = false;
+    while !done.load(Ordering::Relaxed) {
+        thread::sleep(PROGRESS_INTERVAL);
+
+        let status = match CommandWriter::new(socket).and_then(|bus| bus.send(Command::Status)) {
+            Ok(CommandResult::Status(status)) => status,
+            _ => break,
+        };
+        let transfer = status
+            .transfers
+            .into_iter()
+            .find(|transfer| transfer.direction == Direction::Download && transfer.path == path);
+        if let Some(transfer) = transfer {
+            let progress = match transfer.size {
+                Some(size) if size > 0 => format!(
+                    "{} / {} ({}%)",
+                    format_bytes(transfer.transferred),
+                    format_bytes(size),
+                    transfer.transferred * 100 / size
+                ),
+                _ => format_bytes(transfer.transferred),
+            };
+            eprint!("\rdownloading {}: {}\x1b[K", path, progress);
+            let _ = std::io::stderr().flush();
+            reported = true;
+        }
+    }
+
+    if reported {
+        eprintln!();
+    }
+}
@@ -1,15 +1,122 @@
+use crate::cli::local_or_remote;
+use crate::cli::output::OutputFormat;
+use crate::command::protocol::{CommandResult, FileRecord, SyncState};
 use crate::command::Command;
+use crate::status::format_bytes;
 use crate::{CommandWriter, Handler, Result};
-use clap::Args;
+use anyhow::anyhow;
+use clap::{ArgEnum, Args};
+use prettytable::{format, row, Table};
 
 /// List the files synchronized in the system
 #[derive(Debug, Args)]
-pub struct ListCommand;
+pub struct ListCommand {
+    /// Only list the files under this path, either a remote path, e.g `docs/reports`, or a local one
+    prefix: Option<String>,
+
+    /// Only list the files whose remote path matches a glob pattern, e.g `docs/**/*.pdf`
+    #[clap(short, long)]
+    glob: Option<String>,
+
+    /// List the deleted files too
+    #[clap(long)]
+    deleted: bool,
+
+    /// The order in which the files are listed
+    #[clap(short, long, arg_enum, default_value = "path")]
+    sort: SortKey,
+
+    /// Display the size, the last update, the device which made it and the sync state of the files
+    #[clap(short, long)]
+    long: bool,
+}
+
+/// The key by which the files are sorted.
+#[derive(Debug, Clone, Copy, PartialEq, ArgEnum)]
+enum SortKey {
+    Path,
+    Name,
+    Version,
+    /// The last modification, from the newest to the oldest
+    Modified,
+}
 
 impl Handler for ListCommand {
-    fn handler(&self, command_bus: CommandWriter) -> Result<()> {
-        let response = command_bus.send(Command::ListFiles)?;
-        println!("{}", response);
-        Ok(())
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        let mut files = match command_bus.send(Command::ListFiles {
+            prefix: self.prefix.as_deref().map(local_or_remote),
+            glob: self.glob.clone(),
+            deleted: self.deleted,
+        })? {
+            CommandResult::Files(files) => files,
+            result => return Err(anyhow!("unexpected response. result={:?}", result)),
+        };
+        sort(&mut files, self.sort);
+
+        output.print(&files, |files| {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_CLEAN);
+            if self.long {
+                table.add_row(row![
+                    "FILENAME",
+                    "PATH",
+                    "VERSION",
+                    "SIZE",
+                    "LAST UPDATED",
+                    "DEVICE",
+                    "STATE"
+                ]);
+            } else {
+                table.add_row(row!["FILENAME", "PATH", "VERSION", "SYNCED"]);
+            }
+
+            for file in files {
+                let version = file
+                    .version
+                    .map(|version| version.to_string())
+                    .unwrap_or_default();
+                if !self.long {
+                    let synced = file.state == SyncState::Synced;
+                    table.add_row(row![file.name, file.path, version, synced]);
+                    continue;
+                }
+
+                let size = if file.deleted {
+                    String::from("deleted")
+                } else {
+                    file.size.map(format_bytes).unwrap_or_default()
+                };
+                let last_updated = file
+                    .last_updated
+                    .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                table.add_row(row![
+                    file.name,
+                    file.path,
+                    version,
+                    size,
+                    last_updated,
+                    file.device.clone().unwrap_or_default(),
+                    file.state
+                ]);
+            }
+            vec![table]
+        })
+    }
+}
+
+/// Sort the files by `key`.
+fn sort(files: &mut [FileRecord], key: SortKey) {
+    match key {
+        SortKey::Path => files.sort_by(|a, b| a.path.cmp(&b.path)),
+        SortKey::Name => files.sort_by(|a, b| a.name.cmp(&b.name).then(a.path.cmp(&b.path))),
+        SortKey::Version => {
+            files.sort_by(|a, b| a.version.cmp(&b.version).then(a.path.cmp(&b.path)))
+        }
+        SortKey::Modified => files.sort_by(|a, b| {
+            b.last_updated
+                .cmp(&a.last_updated)
+                .then(a.path.cmp(&b.path))
+        }),
     }
 }
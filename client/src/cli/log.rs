@@ -0,0 +1,58 @@
+use crate::cli::local_or_remote;
+use crate::cli::output::OutputFormat;
+use crate::command::protocol::CommandResult;
+use crate::command::Command;
+use crate::status::format_bytes;
+use crate::{CommandWriter, Handler, Result};
+use anyhow::anyhow;
+use clap::Args;
+use prettytable::{format, row, Table};
+
+/// List the versions of a file
+#[derive(Debug, Args)]
+pub struct LogCommand {
+    /// The path of the file, either a remote path, e.g `docs/report.pdf`, or a local one
+    path: String,
+}
+
+impl Handler for LogCommand {
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        let versions = match command_bus.send(Command::Log {
+            path: local_or_remote(&self.path),
+        })? {
+            CommandResult::Versions(versions) => versions,
+            result => return Err(anyhow!("unexpected response. result={:?}", result)),
+        };
+
+        output.print(&versions, |versions| {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_CLEAN);
+            table.add_row(row!["VERSION", "DATE", "DEVICE", "SIZE", "HASH"]);
+            // The newest version first
+            for version in versions.iter().rev() {
+                let size = if version.deleted {
+                    String::from("deleted")
+                } else {
+                    version.size.map(format_bytes).unwrap_or_default()
+                };
+                let date = version
+                    .last_updated
+                    .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                let hash = version
+                    .hash
+                    .as_deref()
+                    .map(|hash| hash.chars().take(12).collect::<String>())
+                    .unwrap_or_default();
+                table.add_row(row![
+                    version.version,
+                    date,
+                    version.device.clone().unwrap_or_default(),
+                    size,
+                    hash
+                ]);
+            }
+            vec![table]
+        })
+    }
+}
@@ -1 +1,34 @@
+pub mod conflicts;
+pub mod get;
 pub mod list;
+pub mod log;
+pub mod output;
+pub mod restore;
+pub mod status;
+pub mod watch;
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Make a path absolute, as the daemon does not run in the working directory of the CLI.
+///
+/// Paths are resolved the same way the `notify` watcher does, so the paths of the watch events
+/// match the watched ones.
+pub fn absolute(path: &str) -> Result<String> {
+    if Path::new(path).is_absolute() {
+        return Ok(path.to_string());
+    }
+    Ok(std::env::current_dir()?.join(path).display().to_string())
+}
+
+/// Make the path of a local file absolute, as the daemon does not run in the working directory
+/// of the CLI. A path which does not exist locally is kept as is, as a remote path.
+pub fn local_or_remote(path: &str) -> String {
+    let local = Path::new(path);
+    if local.is_absolute() || !local.exists() {
+        return path.to_string();
+    }
+    std::env::current_dir()
+        .map(|dir| dir.join(local).display().to_string())
+        .unwrap_or_else(|_| path.to_string())
+}
@@ -0,0 +1,121 @@
+use anyhow::Result;
+use clap::ArgEnum;
+use prettytable::{row, Table};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{stdout, Write};
+
+/// The format in which the CLI prints the results of the commands.
+#[derive(Debug, Clone, Copy, PartialEq, ArgEnum)]
+pub enum OutputFormat {
+    /// Tables, for humans
+    Table,
+    Json,
+    Yaml,
+    /// The rows of the tables, as comma-separated values
+    Csv,
+}
+
+impl OutputFormat {
+    /// Print the result of a command.
+    ///
+    /// The result is serialized as is in JSON and YAML, and rendered into tables by `tables` otherwise.
+    pub fn print<T: Serialize>(
+        &self,
+        result: &T,
+        tables: impl FnOnce(&T) -> Vec<Table>,
+    ) -> Result<()> {
+        self.write(&mut stdout(), result, tables)
+    }
+
+    /// Write the result of a command into `writer`.
+    fn write<W: Write, T: Serialize>(
+        &self,
+        writer: &mut W,
+        result: &T,
+        tables: impl FnOnce(&T) -> Vec<Table>,
+    ) -> Result<()> {
+        match self {
+            OutputFormat::Table => {
+                let tables = tables(result)
+                    .iter()
+                    .map(|table| table.to_string())
+                    .collect::<Vec<String>>();
+                writeln!(writer, "{}", tables.join("\n"))?;
+            }
+            OutputFormat::Csv => {
+                for (i, table) in tables(result).iter().enumerate() {
+                    if i > 0 {
+                        writeln!(writer)?;
+                    }
+                    table.to_csv(&mut *writer)?.flush()?;
+                }
+            }
+            OutputFormat::Json => writeln!(writer, "{}", serde_json::to_string_pretty(result)?)?,
+            OutputFormat::Yaml => write!(writer, "{}", serde_yaml::to_string(result)?)?,
+        }
+        Ok(())
+    }
+
+    /// Print a message reporting the outcome of a command which has no result.
+    pub fn message(&self, message: &str) -> Result<()> {
+        match self {
+            OutputFormat::Table => {
+                println!("{}", message);
+                Ok(())
+            }
+            _ => self.print(&BTreeMap::from([("message", message)]), |_| {
+                let mut table = Table::new();
+                table.add_row(row!["MESSAGE"]);
+                table.add_row(row![message]);
+                vec![table]
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::output::OutputFormat;
+    use crate::command::protocol::WatchRecord;
+    use prettytable::{row, Table};
+
+    fn render(output: OutputFormat, watches: &[WatchRecord]) -> String {
+        let mut buffer = vec![];
+        output
+            .write(&mut buffer, &watches, |watches| {
+                let mut table = Table::new();
+                table.add_row(row!["PATH", "ROOT"]);
+                for watch in watches.iter() {
+                    table.add_row(row![watch.path, watch.root.clone().unwrap_or_default()]);
+                }
+                vec![table]
+            })
+            .expect("failed to render output");
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_it_render_records_in_every_format() {
+        let watches = vec![WatchRecord {
+            path: String::from("/tmp/docs, notes"),
+            root: Some(String::from("tmp")),
+        }];
+
+        assert!(render(OutputFormat::Table, &watches).contains("/tmp/docs, notes"));
+        assert_eq!(
+            render(OutputFormat::Csv, &watches),
+            "PATH,ROOT\n\"/tmp/docs, notes\",tmp\n"
+        );
+        assert_eq!(
+            serde_json::from_str::<Vec<WatchRecord>>(&render(OutputFormat::Json, &watches))
+                .unwrap(),
+            watches
+        );
+        assert_eq!(
+            serde_yaml::from_str::<Vec<WatchRecord>>(&render(OutputFormat::Yaml, &watches))
+                .unwrap(),
+            watches
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use crate::cli::local_or_remote;
+use crate::cli::output::OutputFormat;
+use crate::command::Command;
+use crate::{CommandWriter, Handler, Result};
+use clap::Args;
+
+/// Restore a version of a file, which becomes its latest version
+#[derive(Debug, Args)]
+pub struct RestoreCommand {
+    /// The path of the file, either a remote path, e.g `docs/report.pdf`, or a local one
+    path: String,
+
+    /// The version to restore, as listed by `polydrive log`
+    #[clap(long)]
+    version: i32,
+}
+
+impl Handler for RestoreCommand {
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        command_bus.send(Command::Restore {
+            path: local_or_remote(&self.path),
+            version: self.version,
+        })?;
+        output.message(&format!(
+            "restored version {} of {}",
+            self.version, self.path
+        ))
+    }
+}
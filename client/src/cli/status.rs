@@ -0,0 +1,87 @@
+use crate::cli::output::OutputFormat;
+use crate::command::protocol::{CommandResult, StatusRecord};
+use crate::command::Command;
+use crate::status::format_bytes;
+use crate::{CommandWriter, Handler, Result};
+use anyhow::anyhow;
+use clap::Args;
+use prettytable::{format, row, Table};
+
+/// Report the status of the daemon
+#[derive(Debug, Args)]
+pub struct StatusCommand;
+
+impl Handler for StatusCommand {
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        let status = match command_bus.send(Command::Status)? {
+            CommandResult::Status(status) => status,
+            result => return Err(anyhow!("unexpected response. result={:?}", result)),
+        };
+
+        output.print(&status, tables)
+    }
+}
+
+/// Render the status of the daemon, and the transfers in progress.
+fn tables(status: &StatusRecord) -> Vec<Table> {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_CLEAN);
+    table.add_row(row!["CONNECTION", status.connection]);
+
+    let roots = status
+        .roots
+        .iter()
+        .map(|root| format!("{} ({})", root.name, root.path.display()))
+        .collect::<Vec<String>>();
+    table.add_row(row!["ROOTS", roots.join("\n")]);
+
+    let last_sync = status
+        .last_sync
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| String::from("never"));
+    table.add_row(row!["LAST SYNC", last_sync]);
+
+    let last_error = status
+        .last_error
+        .as_ref()
+        .map(|error| {
+            format!(
+                "{} ({})",
+                error.message,
+                error.time.format("%Y-%m-%d %H:%M:%S")
+            )
+        })
+        .unwrap_or_else(|| String::from("none"));
+    table.add_row(row!["LAST ERROR", last_error]);
+    table.add_row(row![
+        "PENDING",
+        format!(
+            "{} operation(s), {} retrying, {} failed",
+            status.pending, status.retrying, status.failed
+        )
+    ]);
+
+    // The transfers in progress
+    let mut transfers = Table::new();
+    transfers.set_format(*format::consts::FORMAT_CLEAN);
+    transfers.add_row(row!["DIRECTION", "PATH", "PROGRESS", "STARTED"]);
+    for transfer in &status.transfers {
+        let progress = match transfer.size {
+            Some(size) if size > 0 => format!(
+                "{} / {} ({}%)",
+                format_bytes(transfer.transferred),
+                format_bytes(size),
+                transfer.transferred * 100 / size
+            ),
+            _ => format_bytes(transfer.transferred),
+        };
+        transfers.add_row(row![
+            transfer.direction,
+            transfer.path,
+            progress,
+            transfer.started.format("%H:%M:%S")
+        ]);
+    }
+
+    vec![table, transfers]
+}
@@ -0,0 +1,58 @@
+use crate::cli::absolute;
+use crate::cli::output::OutputFormat;
+use crate::command::protocol::CommandResult;
+use crate::command::Command;
+use crate::{CommandWriter, Handler, Result};
+use anyhow::anyhow;
+use clap::{Args, Subcommand};
+use prettytable::{format, row, Table};
+
+/// Manage the paths watched by the daemon
+#[derive(Debug, Args)]
+pub struct WatchCommand {
+    #[clap(subcommand)]
+    action: WatchAction,
+}
+
+#[derive(Debug, Subcommand)]
+enum WatchAction {
+    /// Start watching a file, a directory or a glob pattern
+    Add { path: String },
+    /// Stop watching a path previously added
+    Remove { path: String },
+    /// List the watched paths
+    List,
+}
+
+impl Handler for WatchCommand {
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()> {
+        match &self.action {
+            WatchAction::Add { path } => {
+                let path = absolute(path)?;
+                command_bus.send(Command::WatchAdd { path: path.clone() })?;
+                output.message(&format!("watching {}", path))
+            }
+            WatchAction::Remove { path } => {
+                let path = absolute(path)?;
+                command_bus.send(Command::WatchRemove { path: path.clone() })?;
+                output.message(&format!("stopped watching {}", path))
+            }
+            WatchAction::List => {
+                let watches = match command_bus.send(Command::WatchList)? {
+                    CommandResult::Watches(watches) => watches,
+                    result => return Err(anyhow!("unexpected response. result={:?}", result)),
+                };
+
+                output.print(&watches, |watches| {
+                    let mut table = Table::new();
+                    table.set_format(*format::consts::FORMAT_CLEAN);
+                    table.add_row(row!["PATH", "ROOT"]);
+                    for watch in watches {
+                        table.add_row(row![watch.path, watch.root.clone().unwrap_or_default()]);
+                    }
+                    vec![table]
+                })
+            }
+        }
+    }
+}
@@ -1,51 +1,606 @@
+use crate::command::protocol::{
+    CommandError, CommandResult, DownloadRecord, ErrorCode, FileRecord, StatusRecord, SyncState,
+    VersionRecord, WatchRecord,
+};
 use crate::command::Command;
+use crate::grpc::file::{File, FileRequest};
 use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
+use crate::grpc::server::GetVersionsRequest;
+use crate::identity::Identity;
+use crate::queue::{Operation, Queue};
+use crate::reconciler::{local_files, Reconciler};
+use crate::roots::{base_dir, Roots};
+use crate::state::State;
+use crate::status::Status;
+use crate::storage_manager::StorageManager;
+use crate::watcher::WatchHandle;
 use anyhow::Result;
-use log::info;
-use prettytable::{cell, format, row, Table};
-use std::path::PathBuf;
+use chrono::{DateTime, Local, TimeZone};
+use glob::{MatchOptions, Pattern};
+use log::{error, info};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use tonic::transport::Channel;
+use tonic::Code;
+
+/// The files selected by the `list` command.
+struct FileFilter {
+    /// The remote path the files must be under
+    prefix: Option<String>,
+    /// The pattern the remote path of the files must match
+    glob: Option<Pattern>,
+    /// Whether the deleted files are selected
+    deleted: bool,
+}
+
+impl FileFilter {
+    /// Check whether the file at the remote `path` is selected.
+    fn selects(&self, path: &str, deleted: bool) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        (self.deleted || !deleted)
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| Path::new(path).starts_with(prefix))
+            && self
+                .glob
+                .as_ref()
+                .is_none_or(|glob| glob.matches_with(path, options))
+    }
+}
 
 /// The `CommandHandler` is responsible of handling commands
 /// that cames from the client CLI.
-#[derive(Debug)]
 pub struct CommandHandler {
-    #[allow(dead_code)]
     client: FileManagerServiceClient<Channel>,
+    /// The identity of the device, sent along with the requests
+    identity: Identity,
+    /// The state of the synchronized files
+    state: State,
+    /// The sync roots, to find the local copy of the files
+    roots: Roots,
+    /// The paths watched by the daemon
+    watches: WatchHandle,
+    /// The reconciler, to index the files of newly watched paths
+    reconciler: Reconciler,
+    /// The operations waiting to be indexed
+    queue: Queue,
+    /// The live status of the daemon
+    status: Status,
+    /// The storage manager, to download the versions to restore
+    storage_manager: StorageManager,
 }
 
 impl CommandHandler {
-    pub fn new(client: FileManagerServiceClient<Channel>) -> Self {
-        Self { client }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        client: FileManagerServiceClient<Channel>,
+        identity: Identity,
+        state: State,
+        roots: Roots,
+        watches: WatchHandle,
+        reconciler: Reconciler,
+        queue: Queue,
+        status: Status,
+    ) -> Self {
+        Self {
+            storage_manager: StorageManager::init(client.clone(), identity.clone(), status.clone()),
+            client,
+            identity,
+            state,
+            roots,
+            watches,
+            reconciler,
+            queue,
+            status,
+        }
     }
 
-    /// Execute the command supplied in arguments and return it's output.
-    pub async fn execute(&self, command: Command) -> Result<String> {
+    /// Execute the command supplied in arguments and return its result.
+    pub async fn execute(&self, command: Command) -> Result<CommandResult, CommandError> {
         match command {
-            Command::ListFiles => self.list().await,
-            _ => Ok(String::from("command not found")),
+            Command::ListFiles {
+                prefix,
+                glob,
+                deleted,
+            } => {
+                let filter = self.file_filter(prefix, glob, deleted)?;
+                Ok(self.list(&filter).await?)
+            }
+            Command::ListConflicts => Ok(CommandResult::Conflicts(self.state.conflicts()?)),
+            Command::Log { path } => self.log(&path).await,
+            Command::Get { path, version, to } => self.get(&path, version, to).await,
+            Command::Restore { path, version } => self.restore(&path, version).await,
+            Command::WatchAdd { path } => self.watch_add(&path),
+            Command::WatchRemove { path } => self.watch_remove(&path),
+            Command::WatchList => Ok(self.watch_list()),
+            Command::Status => Ok(self.status()?),
         }
     }
 
-    /// List the files indexed
-    pub async fn list(&self) -> Result<String> {
-        info!("getting files from server");
-        // Create the table
-        let mut table = Table::new();
-        table.set_format(*format::consts::FORMAT_CLEAN);
-        table.add_row(row!["FILENAME", "PATH", "VERSION", "SYNCED"]);
+    /// Build the filter of the `list` command from its arguments.
+    ///
+    /// The prefix is either a remote path, or an absolute local path under a sync root.
+    fn file_filter(
+        &self,
+        prefix: Option<String>,
+        glob: Option<String>,
+        deleted: bool,
+    ) -> Result<FileFilter, CommandError> {
+        let prefix = prefix.map(|prefix| self.remote_path(&prefix)).transpose()?;
+        let glob = glob
+            .map(|glob| Pattern::new(&glob))
+            .transpose()
+            .map_err(|e| {
+                CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    &format!("invalid glob pattern. details={}", e),
+                )
+            })?;
+
+        Ok(FileFilter {
+            prefix,
+            glob,
+            deleted,
+        })
+    }
 
+    /// List the files indexed, along with the watched files which are not indexed yet.
+    async fn list(&self, filter: &FileFilter) -> Result<CommandResult> {
+        info!("getting files from server");
         let response = self.client.clone().get_files(()).await?.into_inner();
+
+        let pending = self
+            .queue
+            .pending()?
+            .into_iter()
+            .map(|pending| pending.path)
+            .collect::<HashSet<PathBuf>>();
+        // A conflict is over once its copy was removed
+        let conflicted = self
+            .state
+            .conflicts()?
+            .into_iter()
+            .filter(|conflict| Path::new(&conflict.copy).exists())
+            .map(|conflict| PathBuf::from(conflict.path))
+            .collect::<HashSet<PathBuf>>();
+
+        let mut files = vec![];
+        let mut indexed = HashSet::new();
         for file in response.data {
-            let is_sync = PathBuf::from(&file.path).exists();
-            table.add_row(row![
-                file.base_name,
-                file.path,
-                file.version.unwrap_or(1),
-                is_sync
-            ]);
+            indexed.insert(file.path.clone());
+            if !filter.selects(&file.path, file.deleted) {
+                continue;
+            }
+
+            files.push(FileRecord {
+                state: self.sync_state(&file, &pending, &conflicted)?,
+                size: (!file.deleted).then_some(file.size),
+                last_updated: file.last_updated.and_then(|time| {
+                    Local
+                        .timestamp_opt(time.seconds, time.nanos as u32)
+                        .single()
+                }),
+                name: file.base_name,
+                path: file.path,
+                version: Some(file.version.unwrap_or(1)),
+                deleted: file.deleted,
+                device: file.device,
+            });
+        }
+
+        for local in local_files(&self.watches.paths()) {
+            let path = match self.roots.to_remote(&local) {
+                Ok(path) if self.watches.includes(&local) => path,
+                _ => continue,
+            };
+            if indexed.contains(&path) || !filter.selects(&path, false) {
+                continue;
+            }
+
+            let metadata = std::fs::metadata(&local).ok();
+            files.push(FileRecord {
+                name: local
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                path,
+                version: None,
+                deleted: false,
+                size: metadata.as_ref().map(|metadata| metadata.len()),
+                last_updated: metadata
+                    .and_then(|metadata| metadata.modified().ok())
+                    .map(DateTime::from),
+                device: None,
+                state: SyncState::LocalOnly,
+            });
+        }
+
+        Ok(CommandResult::Files(files))
+    }
+
+    /// Compare the local copy of a file with its latest remote version.
+    fn sync_state(
+        &self,
+        file: &File,
+        pending: &HashSet<PathBuf>,
+        conflicted: &HashSet<PathBuf>,
+    ) -> Result<SyncState> {
+        let local = match self.roots.to_local(&file.path) {
+            Ok(Some(local)) if self.watches.includes(&local) => local,
+            _ => return Ok(SyncState::RemoteOnly),
+        };
+
+        if conflicted.contains(&local) {
+            return Ok(SyncState::Conflicted);
+        }
+        if pending.contains(&local) {
+            return Ok(SyncState::Pending);
+        }
+        if !local.exists() {
+            return Ok(if file.deleted {
+                SyncState::Synced
+            } else {
+                SyncState::RemoteOnly
+            });
+        }
+
+        let synced = match self.state.get(&file.path)? {
+            Some(state) => {
+                !file.deleted
+                    && !state.deleted
+                    && state.version == file.version.unwrap_or(1)
+                    && !self.state.is_modified(&file.path, &local)?
+            }
+            None => false,
+        };
+        Ok(if synced {
+            SyncState::Synced
+        } else {
+            SyncState::Pending
+        })
+    }
+
+    /// List the versions of a file.
+    pub async fn log(&self, path: &str) -> Result<CommandResult, CommandError> {
+        let path = self.remote_path(path)?;
+        info!("getting versions from server. path={}", &path);
+        let response = self
+            .client
+            .clone()
+            .get_versions(GetVersionsRequest {
+                path,
+                client_name: Some(self.identity.client()),
+            })
+            .await
+            .map_err(remote_error)?
+            .into_inner();
+
+        Ok(CommandResult::Versions(
+            response
+                .versions
+                .into_iter()
+                .map(|file| VersionRecord {
+                    version: file.version.unwrap_or(1),
+                    deleted: file.deleted,
+                    size: (!file.deleted).then_some(file.size),
+                    hash: Some(file.hash).filter(|hash| !hash.is_empty()),
+                    last_updated: timestamp(file.last_updated),
+                    device: file.device,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Download a file, or one of its versions, into `to` or else into its local copy.
+    ///
+    /// A file downloaded into its local copy is recorded as synchronized, so it is not indexed
+    /// again as a new version.
+    pub async fn get(
+        &self,
+        path: &str,
+        version: Option<i32>,
+        to: Option<String>,
+    ) -> Result<CommandResult, CommandError> {
+        let key = self.remote_path(path)?;
+        let name = key.rsplit('/').next().unwrap_or(&key).to_string();
+        let (local, tracked) = match (to, version) {
+            (Some(to), _) => {
+                let to = PathBuf::from(to);
+                if to.is_dir() {
+                    (to.join(&name), false)
+                } else {
+                    (to, false)
+                }
+            }
+            (None, None) => match self.roots.to_local(&key) {
+                // Only a watched copy is kept synchronized
+                Ok(Some(local)) => {
+                    let tracked = self.watches.includes(&local);
+                    (local, tracked)
+                }
+                _ => {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidRequest,
+                        &format!(
+                            "file is not under any sync root, a destination is required. path={}",
+                            &key
+                        ),
+                    ))
+                }
+            },
+            // An older version in place would be indexed as a new one
+            (None, Some(_)) => return Err(CommandError::new(
+                ErrorCode::InvalidRequest,
+                "a destination is required to get a version, use restore to bring it back in place",
+            )),
+        };
+        if tracked && self.state.is_modified(&key, &local)? {
+            return Err(CommandError::new(
+                ErrorCode::AlreadyExists,
+                &format!(
+                    "local changes are not indexed yet, try again once synchronized. path={}",
+                    local.display()
+                ),
+            ));
+        }
+
+        info!(
+            "getting file. path={}, version={:?}, local={}",
+            &key,
+            version,
+            &local.display()
+        );
+        let response = self
+            .client
+            .clone()
+            .file(FileRequest {
+                client_name: Some(self.identity.client()),
+                path: key.clone(),
+                version: version.map(|version| version.to_string()),
+            })
+            .await
+            .map_err(remote_error)?
+            .into_inner();
+        let file = response.file.unwrap_or_default();
+        if file.deleted {
+            return Err(CommandError::new(
+                ErrorCode::NotFound,
+                &format!("file is deleted. path={}, version={:?}", &key, version),
+            ));
+        }
+
+        let destination = local.display().to_string();
+        // The local copy is replaced by a version the server already knows
+        if tracked {
+            self.watches.expect_change(&local, Some(&file.hash));
         }
+        let hash = self
+            .storage_manager
+            .download(&response.link, &key, &destination, &file.hash)
+            .await?;
+        let version = file.version.unwrap_or(1);
+        if tracked {
+            self.state.track(&key, version, false, &local)?;
+        }
+
+        Ok(CommandResult::Download(DownloadRecord {
+            path: key,
+            version,
+            size: std::fs::metadata(&local)
+                .map_err(anyhow::Error::from)?
+                .len(),
+            local: destination,
+            hash,
+        }))
+    }
+
+    /// Replace the local copy of a file with one of its versions, which is then indexed as
+    /// its latest version.
+    pub async fn restore(&self, path: &str, version: i32) -> Result<CommandResult, CommandError> {
+        let key = self.remote_path(path)?;
+        let local = match self.roots.to_local(&key) {
+            Ok(Some(local)) if self.watches.includes(&local) => local,
+            _ => {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidRequest,
+                    &format!("path is not watched. path={}", path),
+                ))
+            }
+        };
+        // The restored version replaces the local copy, which must be known to the server
+        if self.state.is_modified(&key, &local)? {
+            return Err(CommandError::new(
+                ErrorCode::InvalidRequest,
+                &format!(
+                    "local changes are not indexed yet, try again once synchronized. path={}",
+                    path
+                ),
+            ));
+        }
+
+        info!("restoring file. path={}, version={}", &key, version);
+        let response = self
+            .client
+            .clone()
+            .file(FileRequest {
+                client_name: Some(self.identity.client()),
+                path: key.clone(),
+                version: Some(version.to_string()),
+            })
+            .await
+            .map_err(remote_error)?
+            .into_inner();
+        let file = response.file.unwrap_or_default();
+        if file.deleted {
+            return Err(CommandError::new(
+                ErrorCode::InvalidRequest,
+                &format!("version is a deletion. path={}, version={}", &key, version),
+            ));
+        }
+
+        self.storage_manager
+            .download(
+                &response.link,
+                &key,
+                &local.display().to_string(),
+                &file.hash,
+            )
+            .await?;
+        self.queue
+            .push(&local, Operation::Index { created: false })?;
+        Ok(CommandResult::Done)
+    }
+
+    /// Get the remote path of a file from its path, either remote or absolute local.
+    fn remote_path(&self, path: &str) -> Result<String, CommandError> {
+        if !Path::new(path).is_absolute() {
+            return Ok(path.to_string());
+        }
+        self.roots
+            .to_remote(Path::new(path))
+            .map_err(|e| CommandError::new(ErrorCode::NotFound, &e.to_string()))
+    }
+
+    /// Start watching a path, and index the files already present under it.
+    pub fn watch_add(&self, path: &str) -> Result<CommandResult, CommandError> {
+        if self
+            .watches
+            .patterns()
+            .iter()
+            .any(|watched| watched == path)
+        {
+            return Err(CommandError::new(
+                ErrorCode::AlreadyExists,
+                &format!("path is already watched. path={}", path),
+            ));
+        }
+        if !base_dir(path).exists() {
+            return Err(CommandError::new(
+                ErrorCode::NotFound,
+                &format!("path does not exist. path={}", path),
+            ));
+        }
+        self.watches.add(path)?;
+
+        let reconciler = self.reconciler.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = reconciler.reconcile().await {
+                error!(
+                    "an error occurred when indexing the new watched path. details={}",
+                    e
+                )
+            }
+        });
+
+        Ok(CommandResult::Done)
+    }
+
+    /// Stop watching a path.
+    pub fn watch_remove(&self, path: &str) -> Result<CommandResult, CommandError> {
+        if !self
+            .watches
+            .patterns()
+            .iter()
+            .any(|watched| watched == path)
+        {
+            return Err(CommandError::new(
+                ErrorCode::NotFound,
+                &format!("path is not watched. path={}", path),
+            ));
+        }
+        self.watches.remove(path)?;
+        Ok(CommandResult::Done)
+    }
+
+    /// List the watched paths, along with the sync root they belong to.
+    pub fn watch_list(&self) -> CommandResult {
+        CommandResult::Watches(
+            self.watches
+                .patterns()
+                .into_iter()
+                .map(|pattern| WatchRecord {
+                    root: self.roots.find(&base_dir(&pattern)).map(|root| root.name),
+                    path: pattern,
+                })
+                .collect(),
+        )
+    }
+
+    /// Report the status of the daemon.
+    pub fn status(&self) -> Result<CommandResult> {
+        let pending = self.queue.pending()?;
+        Ok(CommandResult::Status(StatusRecord {
+            connection: self.status.connection(),
+            roots: self.roots.all(),
+            last_sync: self.status.last_sync(),
+            last_error: self.status.last_error(),
+            retrying: pending
+                .iter()
+                .filter(|p| p.attempts > 0 && !p.parked)
+                .count(),
+            failed: pending.iter().filter(|p| p.parked).count(),
+            pending: pending.len(),
+            transfers: self.status.transfers(),
+        }))
+    }
+}
+
+/// Convert a timestamp sent by the server into a local time.
+fn timestamp(time: Option<prost_types::Timestamp>) -> Option<DateTime<Local>> {
+    time.and_then(|time| {
+        Local
+            .timestamp_opt(time.seconds, time.nanos as u32)
+            .single()
+    })
+}
+
+/// Convert an error returned by the server into an error of the command.
+fn remote_error(status: tonic::Status) -> CommandError {
+    let code = match status.code() {
+        Code::NotFound => ErrorCode::NotFound,
+        Code::InvalidArgument => ErrorCode::InvalidRequest,
+        _ => ErrorCode::Internal,
+    };
+    CommandError::new(code, status.message())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::handler::{remote_error, timestamp, FileFilter};
+    use crate::command::protocol::ErrorCode;
+    use chrono::{TimeZone, Utc};
+    use glob::Pattern;
+
+    #[test]
+    fn test_it_filter_files_by_prefix_glob_and_deletion() {
+        let filter = FileFilter {
+            prefix: Some(String::from("docs/reports")),
+            glob: Some(Pattern::new("docs/*/*.pdf").unwrap()),
+            deleted: false,
+        };
+
+        assert!(filter.selects("docs/reports/q1.pdf", false));
+        assert!(!filter.selects("docs/reports/q1.pdf", true));
+        assert!(!filter.selects("docs/reports-old/q1.pdf", false));
+        assert!(!filter.selects("docs/reports/2022/q1.pdf", false));
+        assert!(!filter.selects("docs/reports/q1.txt", false));
+    }
+
+    #[test]
+    fn test_it_convert_server_responses() {
+        let time = timestamp(Some(prost_types::Timestamp {
+            seconds: 1648807502,
+            nanos: 0,
+        }));
+        assert_eq!(time.unwrap(), Utc.timestamp_opt(1648807502, 0).unwrap());
 
-        Ok(table.to_string())
+        let error = remote_error(tonic::Status::not_found("File not found in database"));
+        assert_eq!(error.code, ErrorCode::NotFound);
+        assert_eq!(error.message, "File not found in database");
     }
 }
@@ -1,26 +1,51 @@
 pub mod handler;
 pub mod pipe;
+pub mod protocol;
 
-#[derive(Debug)]
-pub enum Command {
-    ListFiles,
-    Unknown,
-}
+use serde::{Deserialize, Serialize};
 
-impl From<Command> for &str {
-    fn from(command: Command) -> Self {
-        match command {
-            Command::ListFiles => "list_files",
-            Command::Unknown => "unknown",
-        }
-    }
+/// A command sent by the client CLI to the daemon.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    ListFiles {
+        /// Only list the files under this path, either remote or local
+        prefix: Option<String>,
+        /// Only list the files whose remote path matches this glob pattern
+        glob: Option<String>,
+        /// Whether to list the deleted files too
+        #[serde(default)]
+        deleted: bool,
+    },
+    ListConflicts,
+    /// List the versions of a file, given its remote or local path
+    Log {
+        path: String,
+    },
+    /// Download a file, in its local copy unless a destination is given
+    Get {
+        path: String,
+        version: Option<i32>,
+        to: Option<String>,
+    },
+    /// Make a version of a file its latest version again
+    Restore {
+        path: String,
+        version: i32,
+    },
+    WatchAdd {
+        path: String,
+    },
+    WatchRemove {
+        path: String,
+    },
+    WatchList,
+    Status,
 }
 
-impl From<&str> for Command {
-    fn from(command: &str) -> Self {
-        match command {
-            "list_files" => Self::ListFiles,
-            _ => Self::Unknown,
-        }
+impl Command {
+    /// Whether the command transfers a file, and may then last as long as the transfer.
+    pub fn is_transfer(&self) -> bool {
+        matches!(self, Command::Get { .. } | Command::Restore { .. })
     }
 }
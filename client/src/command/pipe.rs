@@ -1,27 +1,33 @@
+use crate::command::protocol::{
+    parse, read_frame, read_frame_async, write_frame, write_frame_async, CommandError,
+    CommandResult, ErrorCode, Outcome, Request, Response, PROTOCOL_VERSION,
+};
 use crate::command::Command;
 use crate::CommandHandler;
 use anyhow::{anyhow, Result};
-use interprocess::local_socket::{LocalSocketListener, LocalSocketStream};
-use log::{error, info};
+use interprocess::local_socket::tokio::{
+    LocalSocketListener as AsyncLocalSocketListener, LocalSocketStream as AsyncLocalSocketStream,
+};
+use interprocess::local_socket::LocalSocketStream;
+use log::{debug, error, info};
 use std::fs::remove_file;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::PathBuf;
-
-#[derive(Debug)]
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Maximum duration to receive a request once a client is connected.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum duration of the execution of a command, except the transfers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `CommandListener` serves the commands sent on the control socket.
+///
+/// Each connection is handled in its own task, so a slow command does not delay the others.
 pub struct CommandListener {
     /// The socket listener
-    listener: LocalSocketListener,
-    command_handler: CommandHandler,
-}
-
-fn handle_error(conn: std::io::Result<LocalSocketStream>) -> Option<LocalSocketStream> {
-    match conn {
-        Ok(val) => Some(val),
-        Err(error) => {
-            eprintln!("Incoming connection failed: {}", error);
-            None
-        }
-    }
+    listener: AsyncLocalSocketListener,
+    command_handler: Arc<CommandHandler>,
 }
 
 impl CommandListener {
@@ -31,49 +37,101 @@ impl CommandListener {
             if let Err(e) = remove_file(&socket_path) {
                 error!(
                     "failed to remove existing socket file. path={}, details={}",
-                    socket,
-                    e.to_string()
+                    socket, e
                 );
             }
         }
 
-        let listener = LocalSocketListener::bind(socket).map_err(|e| {
+        let listener = AsyncLocalSocketListener::bind(socket).map_err(|e| {
             anyhow!(
                 "failed to bind socket on path. path={}, details={}",
                 socket,
-                e.to_string()
+                e
             )
         })?;
         Ok(Self {
             listener,
-            command_handler,
+            command_handler: Arc::new(command_handler),
         })
     }
 
-    pub async fn listen(&self) -> Result<()> {
+    pub async fn listen(&self) {
         info!("waiting for commands");
 
-        for stream in self.listener.incoming().filter_map(handle_error) {
-            // Read the message received by the client and parse the command
-            let mut reader = BufReader::new(stream);
-            let mut raw = String::new();
-            reader.read_line(&mut raw)?;
+        loop {
+            match self.listener.accept().await {
+                Ok(stream) => {
+                    let command_handler = self.command_handler.clone();
+                    tokio::task::spawn(async move {
+                        if let Err(e) = serve(stream, &command_handler).await {
+                            error!("failed to serve command. details={}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("incoming connection failed. details={}", e),
+            }
+        }
+    }
+}
 
-            // Execute the command
-            let command = Command::from(raw.trim());
-            let response = self.command_handler.execute(command).await?;
+/// Serve the request of a client.
+async fn serve(mut stream: AsyncLocalSocketStream, command_handler: &CommandHandler) -> Result<()> {
+    let data = timeout(READ_TIMEOUT, read_frame_async(&mut stream))
+        .await
+        .map_err(|_| anyhow!("timed out waiting for request"))??;
 
-            // Send the command response to the client
-            let mut writer = BufWriter::new(reader.get_mut());
-            writer.write_all(response.as_bytes())?;
-        }
+    let response = handle(&data, command_handler).await;
+    if let Err(e) = &response {
+        error!("failed to execute command. details={}", e);
+    }
+    write_frame_async(&mut stream, &Response::new(response)).await
+}
 
-        Ok(())
+/// Parse a request and execute its command.
+async fn handle(
+    data: &[u8],
+    command_handler: &CommandHandler,
+) -> std::result::Result<CommandResult, CommandError> {
+    // The version is checked first, as a request of another version may not parse
+    let version = parse::<serde_json::Value>(data)
+        .ok()
+        .and_then(|value| value.get("version").and_then(|version| version.as_u64()));
+    if version != Some(PROTOCOL_VERSION as u64) {
+        return Err(CommandError::new(
+            ErrorCode::UnsupportedVersion,
+            &format!(
+                "unsupported protocol version. version={:?}, supported={}",
+                version, PROTOCOL_VERSION
+            ),
+        ));
     }
+
+    let request = parse::<Request>(data).map_err(|e| {
+        CommandError::new(
+            ErrorCode::InvalidRequest,
+            &format!("invalid request. details={}", e),
+        )
+    })?;
+
+    debug!("executing command. command={:?}", &request.command);
+    // A transfer lasts as long as the file takes to download, a stalled one fails on its own
+    if request.command.is_transfer() {
+        return command_handler.execute(request.command).await;
+    }
+    timeout(REQUEST_TIMEOUT, command_handler.execute(request.command))
+        .await
+        .unwrap_or_else(|_| {
+            Err(CommandError::new(
+                ErrorCode::Timeout,
+                &format!("command timed out. timeout={}s", REQUEST_TIMEOUT.as_secs()),
+            ))
+        })
 }
 
 #[derive(Debug)]
 pub struct CommandWriter {
+    /// The path of the socket
+    socket: String,
     /// The stream where to write data
     stream: LocalSocketStream,
 }
@@ -88,25 +146,30 @@ impl CommandWriter {
             anyhow!(
                 "failed to connect to socket on path. path={}, details={}",
                 socket,
-                e.to_string()
+                e
             )
         })?;
-        Ok(Self { stream })
+        Ok(Self {
+            socket: socket.to_string(),
+            stream,
+        })
     }
 
-    // Send a command onto the pipe, and wait for the response
-    pub fn send(self, command: Command) -> Result<String> {
-        let data: &str = command.into();
+    /// Get the path of the socket, e.g to open other connections to follow the progress of
+    /// a command.
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
 
-        // Send the command to the socket server
+    /// Send a command onto the pipe, and wait for its result.
+    pub fn send(self, command: Command) -> Result<CommandResult> {
         let mut conn = self.stream;
-        conn.write_all(format!("{}\n", data).as_bytes())?;
+        write_frame(&mut conn, &Request::new(command))?;
 
-        // Read the response
-        let mut conn = BufReader::new(conn);
-        let mut buffer = Vec::<u8>::new();
-        conn.read_to_end(&mut buffer)?;
-
-        Ok(String::from_utf8(buffer)?)
+        let response: Response = parse(&read_frame(&mut conn)?)?;
+        match response.outcome {
+            Outcome::Result(result) => Ok(result),
+            Outcome::Error(error) => Err(anyhow!(error)),
+        }
     }
 }
@@ -0,0 +1,363 @@
+//! The protocol spoken on the control socket of the daemon.
+//!
+//! Each message is a frame made of its length, as a big-endian `u32`, followed by a JSON document.
+//! The client sends a `Request` and the daemon answers with a `Response`, both carrying the version
+//! of the protocol, e.g:
+//!
+//! ```json
+//! {"version": 1, "command": {"type": "watch_add", "path": "/home/alice/docs"}}
+//! {"version": 1, "result": {"type": "done"}}
+//! {"version": 1, "error": {"code": "not_found", "message": "path does not exist. path=/home/alice/docs"}}
+//! ```
+
+use crate::command::Command;
+use crate::roots::Root;
+use crate::state::Conflict;
+use crate::status::{Connection, LastError, Transfer};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+/// The version of the protocol.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The maximum size of a request frame. Responses, e.g the list of files, are not limited.
+const MAX_REQUEST_SIZE: usize = 16 * 1024 * 1024;
+
+/// A command sent to the daemon.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Request {
+    pub version: u32,
+    pub command: Command,
+}
+
+impl Request {
+    pub fn new(command: Command) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            command,
+        }
+    }
+}
+
+/// The answer of the daemon to a `Request`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Response {
+    pub version: u32,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl Response {
+    pub fn new(outcome: std::result::Result<CommandResult, CommandError>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            outcome: match outcome {
+                Ok(result) => Outcome::Result(result),
+                Err(error) => Outcome::Error(error),
+            },
+        }
+    }
+}
+
+/// The outcome of a command, either its result or an error.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Result(CommandResult),
+    Error(CommandError),
+}
+
+/// The result of a command.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum CommandResult {
+    /// The command succeeded, and has nothing to report
+    Done,
+    Files(Vec<FileRecord>),
+    Versions(Vec<VersionRecord>),
+    Download(DownloadRecord),
+    Conflicts(Vec<Conflict>),
+    Watches(Vec<WatchRecord>),
+    Status(StatusRecord),
+}
+
+/// A synchronized file, as known by the server and the client.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileRecord {
+    pub name: String,
+    /// The remote path of the file
+    pub path: String,
+    /// The latest version of the file, unless it is only known locally
+    pub version: Option<i32>,
+    /// Whether the latest version is a tombstone
+    pub deleted: bool,
+    pub size: Option<u64>,
+    /// When the latest version was made, or when the local copy was modified for a file
+    /// which is not indexed yet
+    pub last_updated: Option<DateTime<Local>>,
+    /// The client which made the latest version, if known
+    pub device: Option<String>,
+    pub state: SyncState,
+}
+
+/// A version of a file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct VersionRecord {
+    pub version: i32,
+    /// Whether the version is a tombstone
+    pub deleted: bool,
+    pub size: Option<u64>,
+    /// The BLAKE3 hash of the content, if known
+    pub hash: Option<String>,
+    /// When the version was made
+    pub last_updated: Option<DateTime<Local>>,
+    /// The client which made the version, if known
+    pub device: Option<String>,
+}
+
+/// A file downloaded on demand.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DownloadRecord {
+    /// The remote path of the file
+    pub path: String,
+    pub version: i32,
+    /// Where the file was downloaded
+    pub local: String,
+    pub size: u64,
+    /// The BLAKE3 hash of the content, checked once downloaded
+    pub hash: String,
+}
+
+/// How the local copy of a file compares to its latest version.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncState {
+    /// The local copy matches the latest version
+    Synced,
+    /// A change is waiting to be indexed or downloaded
+    Pending,
+    /// The local changes were kept aside in a conflict copy
+    Conflicted,
+    /// The file has no local copy
+    RemoteOnly,
+    /// The file is not indexed on the server yet
+    LocalOnly,
+}
+
+impl Display for SyncState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncState::Synced => write!(f, "synced"),
+            SyncState::Pending => write!(f, "pending"),
+            SyncState::Conflicted => write!(f, "conflicted"),
+            SyncState::RemoteOnly => write!(f, "remote-only"),
+            SyncState::LocalOnly => write!(f, "local-only"),
+        }
+    }
+}
+
+/// A path watched by the daemon.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WatchRecord {
+    /// The path, or glob pattern
+    pub path: String,
+    /// The name of the sync root of the path
+    pub root: Option<String>,
+}
+
+/// The status of the daemon.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StatusRecord {
+    pub connection: Connection,
+    pub roots: Vec<Root>,
+    pub last_sync: Option<DateTime<Local>>,
+    pub last_error: Option<LastError>,
+    /// The number of operations waiting to be indexed
+    pub pending: usize,
+    /// The number of pending operations which already failed
+    pub retrying: usize,
+    /// The number of pending operations which failed too many times to be attempted again
+    #[serde(default)]
+    pub failed: usize,
+    pub transfers: Vec<Transfer>,
+}
+
+/// The code of an error, for tools to handle it without parsing its message.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request could not be parsed
+    InvalidRequest,
+    /// The version of the request is not supported by the daemon
+    UnsupportedVersion,
+    /// The target of the command does not exist
+    NotFound,
+    /// The target of the command already exists
+    AlreadyExists,
+    /// The command did not complete in time
+    Timeout,
+    /// The command failed
+    Internal,
+}
+
+/// An error returned by the daemon.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::new(ErrorCode::Internal, &error.to_string())
+    }
+}
+
+/// Write a message as a frame.
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    writer.write_all(&encode(message)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a message as a frame, on an asynchronous writer.
+pub async fn write_frame_async<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> Result<()> {
+    writer.write_all(&encode(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a response frame, and return its content.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header)?;
+
+    let mut data = vec![0u8; u32::from_be_bytes(header) as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read a request frame from an asynchronous reader, and return its content.
+pub async fn read_frame_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header).await?;
+
+    let length = u32::from_be_bytes(header) as usize;
+    if length > MAX_REQUEST_SIZE {
+        return Err(anyhow!("request too large. length={}", length));
+    }
+    let mut data = vec![0u8; length];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Encode a message as a frame: its length followed by its JSON document.
+fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let data = serde_json::to_vec(message)?;
+    if u32::try_from(data.len()).is_err() {
+        return Err(anyhow!("message too large. length={}", data.len()));
+    }
+    let mut frame = Vec::with_capacity(data.len() + 4);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+/// Parse the content of a frame.
+pub fn parse<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    Ok(serde_json::from_slice(data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::protocol::{
+        parse, read_frame, read_frame_async, write_frame, write_frame_async, CommandError,
+        CommandResult, ErrorCode, Request, Response, MAX_REQUEST_SIZE,
+    };
+    use crate::command::Command;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_it_exchange_framed_messages() {
+        let request = Request::new(Command::WatchAdd {
+            path: String::from("/tmp/my docs"),
+        });
+        let mut buffer = vec![];
+        write_frame(&mut buffer, &request).expect("failed to write frame");
+
+        let data = read_frame(&mut Cursor::new(buffer)).expect("failed to read frame");
+        assert_eq!(parse::<Request>(&data).unwrap(), request);
+        assert_eq!(
+            String::from_utf8(data).unwrap(),
+            r#"{"version":1,"command":{"type":"watch_add","path":"/tmp/my docs"}}"#
+        );
+    }
+
+    #[test]
+    fn test_it_serialize_results_and_errors() {
+        let result = Response::new(Ok(CommandResult::Done));
+        let error = Response::new(Err(CommandError::new(ErrorCode::NotFound, "no such path")));
+
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"version":1,"result":{"type":"done"}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"version":1,"error":{"code":"not_found","message":"no such path"}}"#
+        );
+        assert!(parse::<Request>(br#"{"version":1,"command":{"type":"unknown"}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_it_exchange_frames_asynchronously() {
+        let mut buffer = futures_util::io::Cursor::new(vec![]);
+        write_frame_async(&mut buffer, &Request::new(Command::Status))
+            .await
+            .expect("failed to write frame");
+
+        let data = read_frame(&mut Cursor::new(buffer.into_inner())).expect("failed to read frame");
+        assert_eq!(parse::<Request>(&data).unwrap().command, Command::Status);
+
+        let mut oversized =
+            futures_util::io::Cursor::new(((MAX_REQUEST_SIZE + 1) as u32).to_be_bytes());
+        assert!(read_frame_async(&mut oversized).await.is_err());
+    }
+
+    #[test]
+    fn test_it_read_responses_larger_than_requests() {
+        let response = Response::new(Ok(CommandResult::Conflicts(vec![])));
+        let mut buffer = vec![];
+        write_frame(&mut buffer, &"x".repeat(MAX_REQUEST_SIZE + 1)).expect("failed to write frame");
+        write_frame(&mut buffer, &response).expect("failed to write frame");
+
+        let mut reader = Cursor::new(buffer);
+        let data = read_frame(&mut reader).expect("failed to read frame");
+        assert_eq!(data.len(), MAX_REQUEST_SIZE + 3);
+        let data = read_frame(&mut reader).expect("failed to read frame");
+        assert_eq!(parse::<Response>(&data).unwrap(), response);
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::PathBuf;
 
@@ -12,6 +13,20 @@ pub struct Config {
     /// The server configuration block
     #[serde(default)]
     pub server: ServerConfig,
+    /// The local data configuration block
+    #[serde(default)]
+    pub data: DataConfig,
+    /// The named sync roots, mapping a remote namespace to a local directory, e.g: `docs: /home/alice/docs`
+    #[serde(default)]
+    pub roots: BTreeMap<String, PathBuf>,
+    /// Patterns of files to never synchronize, in gitignore syntax, e.g: `node_modules/`.
+    ///
+    /// They apply to every root, in addition to the `.polydriveignore` files and the default patterns.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// The stability check configuration block, delaying the upload of files still being written
+    #[serde(default)]
+    pub stability: StabilityConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -26,6 +41,25 @@ pub struct ServerConfig {
     pub scheme: Option<String>,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DataConfig {
+    /// The directory where the client stores its local state, e.g: `~/.local/share/polydrive`.
+    #[serde(default)]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct StabilityConfig {
+    /// How long, in seconds, the size and modification time of a file must stay unchanged before it is uploaded.
+    pub settle_time: u64,
+    /// The maximum time, in seconds, to wait for a file to settle. Past it, the file is uploaded anyway,
+    /// e.g a log file which is always appended to.
+    pub max_wait: u64,
+    /// If set, files are not uploaded while a process holds them open for writing. Only supported on Linux.
+    pub check_writers: bool,
+}
+
 impl Config {
     /// Load the configuration.
     ///
@@ -58,6 +92,7 @@ impl Config {
                     match key.as_str() {
                         "server.host" => config.server.host = value.clone(),
                         "server.scheme" => config.server.scheme = Some(value.clone()),
+                        "data.path" => config.data.path = PathBuf::from(value),
                         _ => warn!(
                             "environment variable {} has no effect on configuration.",
                             Self::key_to_env(key)
@@ -116,6 +151,26 @@ impl Default for ServerConfig {
     }
 }
 
+impl Default for StabilityConfig {
+    fn default() -> Self {
+        Self {
+            settle_time: 3,
+            max_wait: 3600,
+            check_writers: false,
+        }
+    }
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        Self {
+            path: dirs::data_local_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("polydrive"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::Config;
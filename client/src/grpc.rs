@@ -11,6 +11,7 @@ pub mod file {
                 DebouncedEvent::Create(_) => FileEventType::Create,
                 DebouncedEvent::Write(_) => FileEventType::Update,
                 DebouncedEvent::Remove(_) => FileEventType::Delete,
+                DebouncedEvent::Rename(_, _) => FileEventType::Move,
                 _ => FileEventType::Unknown,
             }
         }
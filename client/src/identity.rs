@@ -0,0 +1,96 @@
+use crate::grpc::client::Client;
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::path::Path;
+
+/// Name of the file holding the identity of the device, in the data directory.
+const IDENTITY_FILE: &str = "identity.json";
+
+/// The `Identity` of the device the client runs on, sent to the server on every request so
+/// it can attribute versions to devices, and so the client recognizes its own changes in
+/// the notifications.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Identity {
+    /// A UUID generated on the first start, which never changes
+    pub id: String,
+    pub host_name: String,
+    pub os: String,
+}
+
+impl Identity {
+    /// Load the identity stored in the `dir` directory, creating it on the first start.
+    ///
+    /// The host name and the OS are refreshed, as they may change during the life of a device.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(IDENTITY_FILE);
+        let stored = if path.exists() {
+            let stored =
+                serde_json::from_reader::<_, Identity>(File::open(&path)?).map_err(|e| {
+                    anyhow!(
+                        "failed to read device identity. path={}, details={}",
+                        &path.display(),
+                        e
+                    )
+                })?;
+            Some(stored)
+        } else {
+            None
+        };
+
+        let identity = Self {
+            id: stored
+                .as_ref()
+                .map(|identity| identity.id.clone())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            host_name: hostname::get()
+                .map(|host| host.to_string_lossy().to_string())
+                .unwrap_or_else(|_| String::from("unknown")),
+            os: std::env::consts::OS.to_string(),
+        };
+
+        if stored.as_ref() != Some(&identity) {
+            debug!("saving device identity. path={}", &path.display());
+            create_dir_all(dir)?;
+            serde_json::to_writer_pretty(File::create(&path)?, &identity)?;
+        }
+        info!(
+            "device identity loaded. id={}, host={}",
+            &identity.id, &identity.host_name
+        );
+        Ok(identity)
+    }
+
+    /// Get the identity as sent to the server.
+    pub fn client(&self) -> Client {
+        Client {
+            host_name: self.host_name.clone(),
+            ip: String::new(),
+            id: self.id.clone(),
+            os: self.os.clone(),
+        }
+    }
+
+    /// Check whether a change notified by the server was made by this device.
+    pub fn is_origin(&self, origin: Option<&Client>) -> bool {
+        origin.is_some_and(|origin| origin.id == self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity::Identity;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_keep_the_same_identity_between_restarts() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let identity = Identity::load(tmp.path()).expect("failed to create identity");
+        let reloaded = Identity::load(tmp.path()).expect("failed to load identity");
+
+        assert_eq!(identity, reloaded);
+        assert!(identity.is_origin(Some(&reloaded.client())));
+        assert!(!identity.is_origin(None));
+    }
+}
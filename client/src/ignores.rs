@@ -0,0 +1,216 @@
+use crate::roots::Roots;
+use crate::state::metadata;
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// The name of the files holding the ignore patterns of a directory.
+pub const IGNORE_FILE: &str = ".polydriveignore";
+
+/// Patterns ignored by default: temporary files of editors, office suites and browsers,
+/// and files created by operating systems.
+const DEFAULT_PATTERNS: [&str; 20] = [
+    "*.swp",
+    "*.swo",
+    "*~",
+    ".#*",
+    "#*#",
+    "~$*",
+    ".~lock.*#",
+    "*.tmp",
+    "*.part",
+    "*.crdownload",
+    "*.download/",
+    "*.opdownload",
+    "*.partial",
+    ".com.google.Chrome.*",
+    ".goutputstream-*",
+    "*.aria2",
+    ".DS_Store",
+    "Thumbs.db",
+    "desktop.ini",
+    ".Trash-*/",
+];
+
+/// The patterns of a `.polydriveignore` file.
+#[derive(Debug, Clone)]
+struct IgnoreFile {
+    /// The size and modification time of the file, if it exists
+    metadata: Option<(u64, u64)>,
+    patterns: Gitignore,
+}
+
+/// `Ignores` decides which files must not be synchronized, using the gitignore syntax.
+///
+/// Patterns are read from the `.polydriveignore` files of a sync root and its subdirectories,
+/// a file taking precedence on the ones of its parent directories, and from the global patterns
+/// of the configuration, which come after the default ones.
+#[derive(Debug, Clone)]
+pub struct Ignores {
+    /// The default and configured patterns, applied to every path
+    global: Arc<Gitignore>,
+    /// The `.polydriveignore` files, by directory
+    files: Arc<RwLock<HashMap<PathBuf, IgnoreFile>>>,
+    /// The sync roots, to find the directories holding patterns
+    roots: Roots,
+    /// Directories ignored whatever the patterns, e.g the data directory of the client
+    excluded: Vec<PathBuf>,
+}
+
+impl Ignores {
+    /// Build the ignore rules from the global patterns of the configuration.
+    pub fn new(patterns: &[String], roots: Roots) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("/");
+        for pattern in DEFAULT_PATTERNS
+            .iter()
+            .copied()
+            .chain(patterns.iter().map(String::as_str))
+        {
+            builder.add_line(None, pattern).map_err(|e| {
+                anyhow!("invalid ignore pattern. pattern={}, details={}", pattern, e)
+            })?;
+        }
+
+        Ok(Self {
+            global: Arc::new(builder.build()?),
+            files: Arc::new(RwLock::new(HashMap::new())),
+            roots,
+            excluded: vec![],
+        })
+    }
+
+    /// Ignore everything under a directory, whatever the patterns.
+    ///
+    /// The data directory of the client must be excluded, as it may be under a watched directory,
+    /// e.g `~/.local/share/polydrive` when watching the home directory. Otherwise, each change of
+    /// the state would be indexed, changing the state again.
+    pub fn exclude(mut self, dir: &Path) -> Self {
+        let dir = match std::env::current_dir() {
+            Ok(current) if dir.is_relative() => current.join(dir),
+            _ => dir.to_path_buf(),
+        };
+        self.excluded.push(dir);
+        self
+    }
+
+    /// Check whether a local path is ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        if self.excluded.iter().any(|dir| path.starts_with(dir)) {
+            return true;
+        }
+        let is_dir = path.is_dir();
+
+        if let Some(root) = self.roots.find(path) {
+            // The deepest `.polydriveignore` file matching the path decides
+            let mut dir = path.parent();
+            while let Some(current) = dir.filter(|dir| dir.starts_with(&root.path)) {
+                match self
+                    .patterns(current)
+                    .matched_path_or_any_parents(path, is_dir)
+                {
+                    Match::Ignore(glob) => {
+                        debug!(
+                            "path ignored. path={}, pattern={}",
+                            path.display(),
+                            glob.original()
+                        );
+                        return true;
+                    }
+                    Match::Whitelist(_) => return false,
+                    Match::None => dir = current.parent(),
+                }
+            }
+        }
+
+        self.global
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+    }
+
+    /// Get the patterns of the `.polydriveignore` file of a directory.
+    ///
+    /// Files are read again when they are modified.
+    fn patterns(&self, dir: &Path) -> Gitignore {
+        let file = dir.join(IGNORE_FILE);
+        let modified = metadata(&file).ok();
+
+        if let Some(known) = self.files.read().unwrap().get(dir) {
+            if known.metadata == modified {
+                return known.patterns.clone();
+            }
+        }
+
+        let patterns = match modified {
+            Some(_) => {
+                let (patterns, error) = Gitignore::new(&file);
+                if let Some(e) = error {
+                    warn!(
+                        "failed to parse ignore file. path={}, details={}",
+                        file.display(),
+                        e
+                    );
+                }
+                patterns
+            }
+            None => Gitignore::empty(),
+        };
+
+        self.files.write().unwrap().insert(
+            dir.to_path_buf(),
+            IgnoreFile {
+                metadata: modified,
+                patterns: patterns.clone(),
+            },
+        );
+        patterns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ignores::{Ignores, IGNORE_FILE};
+    use crate::roots::Roots;
+    use std::fs::{create_dir_all, write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_ignore_default_and_global_patterns() {
+        let ignores = Ignores::new(&[String::from("node_modules/")], Roots::default())
+            .expect("failed to build ignores");
+
+        assert!(ignores.is_ignored("/tmp/.report.txt.swp".as_ref()));
+        assert!(ignores.is_ignored("/tmp/video.mp4.crdownload".as_ref()));
+        assert!(ignores.is_ignored("/tmp/.goutputstream-XY12AB".as_ref()));
+        assert!(ignores.is_ignored("/tmp/app/node_modules/a/index.js".as_ref()));
+        assert!(!ignores.is_ignored("/tmp/report.txt".as_ref()));
+
+        let ignores = ignores.exclude("/home/alice/.local/share/polydrive".as_ref());
+        assert!(ignores.is_ignored("/home/alice/.local/share/polydrive/db".as_ref()));
+        assert!(!ignores.is_ignored("/home/alice/.local/share/other.txt".as_ref()));
+    }
+
+    #[test]
+    fn test_it_ignore_patterns_of_root_and_subdirectories() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let roots = Roots::default();
+        roots.add("tmp", tmp.path()).expect("failed to add root");
+        create_dir_all(tmp.path().join("app/logs")).expect("failed to create directories");
+        write(tmp.path().join(IGNORE_FILE), "*.log\nbuild/\n").expect("failed to write file");
+        write(tmp.path().join("app").join(IGNORE_FILE), "!important.log\n")
+            .expect("failed to write file");
+
+        let ignores = Ignores::new(&[], roots).expect("failed to build ignores");
+
+        assert!(ignores.is_ignored(&tmp.path().join("app/logs/debug.log")));
+        assert!(ignores.is_ignored(&tmp.path().join("app/build/main.o")));
+        assert!(!ignores.is_ignored(&tmp.path().join("app/logs/important.log")));
+        assert!(!ignores.is_ignored(&tmp.path().join("app/main.c")));
+
+        write(tmp.path().join("app").join(IGNORE_FILE), "*.c\n").expect("failed to write file");
+        assert!(ignores.is_ignored(&tmp.path().join("app/main.c")));
+    }
+}
@@ -1,38 +1,204 @@
-use crate::grpc::file::{File, FileEventRequest, FileEventType, FileResponse};
+pub mod stability;
+
+use crate::grpc::file::{File, FileEventRequest, FileEventType, FileRequest, FileResponse};
 use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
+use crate::identity::Identity;
+use crate::indexer::stability::Stability;
+use crate::queue::{Operation, Pending, Queue};
+use crate::roots::Roots;
+use crate::state::State;
+use crate::status::Status;
 use crate::storage_manager::StorageManager;
-use crate::watcher::WatcherListener;
-use anyhow::Result;
+use crate::synchronizer::Synchronizer;
+use crate::watcher::{WatchHandle, WatcherListener};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use log::{debug, error, info, warn};
 use notify::DebouncedEvent;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tonic::transport::Channel;
+use tonic::Code;
+
+/// Delay before reading the queue again when it failed.
+const QUEUE_ERROR_DELAY: Duration = Duration::from_secs(5);
 
 /// The `Indexer` is responsible to handle events on files
 /// and to synchronize those files onto the server.
+///
+/// Events are pushed to a persistent queue, which is consumed by `run`, so a change
+/// made while the server is unreachable is indexed once it is back.
 #[derive(Clone)]
 pub struct Indexer {
     /// The file manager gRPC client
     client: FileManagerServiceClient<Channel>,
+    /// The identity of the device, sent along with the events
+    identity: Identity,
     /// The file manager
     storage_manager: StorageManager,
+    /// The state of the synchronized files
+    state: State,
+    /// The sync roots, to compute remote paths
+    roots: Roots,
+    /// The watched paths, to filter the files of moved directories
+    watches: WatchHandle,
+    /// The operations waiting to be indexed
+    queue: Queue,
+    /// The status of the daemon, where the result of the operations is published
+    status: Status,
+    /// Tells whether files are still being written, to not upload them partially
+    stability: Stability,
+    /// Downloads the newer versions of files changed locally on an outdated version
+    synchronizer: Synchronizer,
 }
 
 impl Indexer {
     /// Bootstrap the server
-    pub async fn bootstrap(client: FileManagerServiceClient<Channel>) -> Result<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn bootstrap(
+        client: FileManagerServiceClient<Channel>,
+        identity: Identity,
+        state: State,
+        roots: Roots,
+        watches: WatchHandle,
+        queue: Queue,
+        status: Status,
+        stability: Stability,
+        synchronizer: Synchronizer,
+    ) -> Result<Self> {
         info!("initializing indexer");
 
-        let storage_manager = StorageManager::init(client.clone());
+        let storage_manager =
+            StorageManager::init(client.clone(), identity.clone(), status.clone());
 
         Ok(Self {
             client,
+            identity,
             storage_manager,
+            state,
+            roots,
+            watches,
+            queue,
+            status,
+            stability,
+            synchronizer,
         })
     }
 
+    /// Queue an operation on a file, to be indexed by `run`.
+    pub fn enqueue(&self, path: &Path, operation: Operation) {
+        if let Err(e) = self.queue.push(path, operation) {
+            error!(
+                "failed to queue operation. path={}, details={}",
+                &path.display(),
+                e
+            )
+        }
+    }
+
+    /// Index the queued operations, retrying the failed ones later.
+    ///
+    /// This is a blocking method.
+    pub async fn run(&self) -> Result<()> {
+        info!("starting indexer. pending operations={}", self.queue.len());
+
+        // An error on the queue must not stop the indexing of the next operations
+        loop {
+            let due = self.queue.due().unwrap_or_else(|e| {
+                error!("failed to read the queued operations. details={}", e);
+                vec![]
+            });
+            let mut failed = false;
+            for pending in due {
+                if let Err(e) = self.process(&pending).await {
+                    error!(
+                        "failed to process queued operation. file={}, details={}",
+                        &pending.path.display(),
+                        e
+                    );
+                    failed = true;
+                }
+            }
+            // An operation which could not be rescheduled is still due
+            if failed {
+                tokio::time::sleep(QUEUE_ERROR_DELAY).await;
+            }
+            if let Err(e) = self.queue.wait().await {
+                error!("failed to wait for queued operations. details={}", e);
+                tokio::time::sleep(QUEUE_ERROR_DELAY).await;
+            }
+        }
+    }
+
+    /// Index a queued operation, and schedule a new attempt if it fails.
+    async fn process(&self, pending: &Pending) -> Result<()> {
+        // A file still being written is uploaded once it settles
+        if let Operation::Index { .. } | Operation::Move { .. } = pending.operation {
+            if let Some(delay) = self.stability.wait(&pending.path) {
+                debug!(
+                    "file is not settled yet, postponing. file={}, retry in={}ms",
+                    &pending.path.display(),
+                    delay.as_millis()
+                );
+                return self.queue.postpone(pending, delay);
+            }
+        }
+
+        match self.apply(&pending.path, &pending.operation).await {
+            Ok(()) => {
+                self.status.record_sync();
+                self.queue.done(pending)
+            }
+            Err(e) => {
+                self.status
+                    .record_error(&format!("{}: {}", &pending.path.display(), e));
+                let delay = self.queue.retry(pending, &e.to_string()).map_err(|retry| {
+                    anyhow!(
+                        "failed to schedule a new attempt. details={}, error={}",
+                        retry,
+                        e
+                    )
+                })?;
+                if let Some(delay) = delay {
+                    warn!(
+                        "an error occurred when trying to index the file, retrying later. file={}, attempts={}, retry in={}s, details={}",
+                        &pending.path.display(),
+                        pending.attempts + 1,
+                        delay.as_secs(),
+                        e
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Index a single operation.
+    async fn apply(&self, path: &Path, operation: &Operation) -> Result<()> {
+        // The file may have been removed, or unwatched, since the operation was queued
+        if let Operation::Index { .. } | Operation::Move { .. } = operation {
+            if !path.is_file() || !self.watches.includes(path) {
+                debug!(
+                    "file no longer exists or is no longer watched, skipping. file={}",
+                    &path.display()
+                );
+                return Ok(());
+            }
+        }
+
+        match operation {
+            Operation::Index { created: true } => self.index(path, FileEventType::Create).await,
+            Operation::Index { created: false } => self.index(path, FileEventType::Update).await,
+            Operation::Remove => self.remove(path).await,
+            Operation::Move { from } => {
+                self.move_file(from, path).await?;
+                // Index the changes made to the file after it was moved
+                self.index(path, FileEventType::Update).await
+            }
+        }
+    }
+
     /// Notify the remote server that a new event was emitted
     async fn notify(&self, data: FileEventRequest) -> Result<FileResponse> {
         debug!("sending notify request to remote server");
@@ -40,10 +206,25 @@ impl Indexer {
     }
 
     /// Index a file on the remote server.
-    async fn index(&self, path: &Path, event: FileEventType) -> Result<()> {
+    pub async fn index(&self, path: &Path, event: FileEventType) -> Result<()> {
         info!("indexing new file {}", path.display());
         let file = std::fs::File::open(path)?;
 
+        let key = self.roots.to_remote(path)?;
+        let hash = self.state.hash(path)?;
+        let known = self.state.get(&key)?;
+        if let Some(known) = &known {
+            if !known.deleted && known.hash.as_deref() == Some(hash.as_str()) {
+                debug!(
+                    "file content unchanged, skipping upload. file={}",
+                    path.display()
+                );
+                return self.state.track(&key, known.version, false, path);
+            }
+        }
+
+        // The version the change was made on, so the server rejects it if the file has a newer one
+        let base = known.map_or(0, |known| known.version);
         let filename = path.file_name().unwrap_or_else(|| OsStr::new("file"));
         let extension = path.extension().unwrap_or_else(|| OsStr::new("txt"));
 
@@ -53,34 +234,236 @@ impl Indexer {
         );
         let response = self
             .notify(FileEventRequest {
-                client_name: None,
+                client_name: Some(self.identity.client()),
                 event_type: event.into(),
                 file: Some(File {
-                    path: path.display().to_string(),
-                    base_name: filename.to_str().unwrap().to_string(),
-                    version: None,
+                    path: key.clone(),
+                    base_name: filename.to_string_lossy().to_string(),
+                    version: Some(base),
                     created: None,
                     last_updated: None,
+                    deleted: false,
+                    hash,
+                    size: file.metadata()?.len(),
+                    device: None,
                 }),
+                old_path: None,
             })
-            .await?;
+            .await;
+        let response = match response {
+            Err(e) if is_conflict(&e) => {
+                warn!(
+                    "file has a newer version on the server, downloading it first. file={}, base={}",
+                    path.display(),
+                    base
+                );
+                return self.download_latest(&key).await;
+            }
+            response => response?,
+        };
 
         debug!(
             "received pre-signed url for file={:?}, url={:?}",
             filename, &response.link
         );
 
-        self.storage_manager
-            .upload(&response.link, &path.display().to_string(), file)
+        self.upload(&key, path, &response, file).await
+    }
+
+    /// Download the latest version of a file whose local changes were made on an outdated
+    /// version.
+    ///
+    /// The synchronizer keeps the local changes in a conflict copy, which is then indexed as
+    /// a new file.
+    async fn download_latest(&self, path: &str) -> Result<()> {
+        let file = self
+            .client
+            .clone()
+            .file(FileRequest {
+                client_name: Some(self.identity.client()),
+                path: path.to_string(),
+                version: None,
+            })
+            .await?
+            .into_inner()
+            .file
+            .ok_or_else(|| anyhow!("server answered without file. file={}", path))?;
+        self.synchronizer.synchronize(&file).await
+    }
+
+    /// Index the deletion of a file on the remote server.
+    pub async fn remove(&self, path: &Path) -> Result<()> {
+        let key = self.roots.to_remote(path)?;
+        if !matches!(self.state.get(&key)?, Some(known) if !known.deleted) {
+            debug!(
+                "file unknown to the server, nothing to remove. file={}",
+                &path.display()
+            );
+            return Ok(());
+        }
+
+        let filename = path.file_name().unwrap_or_else(|| OsStr::new("file"));
+        let response = self
+            .notify(FileEventRequest {
+                client_name: Some(self.identity.client()),
+                event_type: FileEventType::Delete.into(),
+                file: Some(File {
+                    version: None,
+                    path: key.clone(),
+                    base_name: filename.to_string_lossy().to_string(),
+                    created: None,
+                    last_updated: None,
+                    deleted: false,
+                    hash: String::new(),
+                    size: 0,
+                    device: None,
+                }),
+                old_path: None,
+            })
+            .await?;
+        self.record(&key, path, &response)?;
+        debug!("removed file. file={}", &path.display());
+        Ok(())
+    }
+
+    /// Upload a file with the link received from the server, and record its new version.
+    ///
+    /// The version is recorded before the upload, as the server notifies every client,
+    /// including this one, as soon as the upload is done. It is restored if the upload fails.
+    async fn upload(
+        &self,
+        path: &str,
+        local: &Path,
+        response: &FileResponse,
+        file: std::fs::File,
+    ) -> Result<()> {
+        let previous = self.state.get(path)?;
+        self.record(path, local, response)?;
+
+        if let Err(e) = self
+            .storage_manager
+            .upload(&response.link, path, file)
+            .await
+        {
+            match previous {
+                Some(state) => self.state.set(path, state)?,
+                None => {
+                    self.state.remove(path)?;
+                }
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Record the version assigned by the server to a file.
+    fn record(&self, path: &str, local: &Path, response: &FileResponse) -> Result<()> {
+        if let Some(file) = &response.file {
+            if let Some(version) = file.version {
+                self.state.track(path, version, file.deleted, local)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index a file moved from `old` to `new` on the remote server.
+    ///
+    /// The move keeps the version history of the file. If the server has no history
+    /// for the old path, it answers with a link and the file is uploaded as a new one.
+    async fn move_file(&self, old: &Path, new: &Path) -> Result<()> {
+        info!("indexing moved file {} -> {}", old.display(), new.display());
+        let filename = new.file_name().unwrap_or_else(|| OsStr::new("file"));
+        let (old_key, new_key) = (self.roots.to_remote(old)?, self.roots.to_remote(new)?);
+
+        let response = self
+            .notify(FileEventRequest {
+                client_name: Some(self.identity.client()),
+                event_type: FileEventType::Move.into(),
+                file: Some(File {
+                    path: new_key.clone(),
+                    base_name: filename.to_string_lossy().to_string(),
+                    version: None,
+                    created: None,
+                    last_updated: None,
+                    deleted: false,
+                    hash: String::new(),
+                    size: 0,
+                    device: None,
+                }),
+                old_path: Some(old_key.clone()),
+            })
             .await?;
 
+        if response.link.is_empty() {
+            // The moved history may be renumbered after the one of the new path
+            self.state.remove(&old_key)?;
+            return self.record(&new_key, new, &response);
+        }
+
+        debug!(
+            "no history found for moved file, uploading it. file={}",
+            new.display()
+        );
+        let file = std::fs::File::open(new)?;
+        self.upload(&new_key, new, &response, file).await
+    }
+
+    /// Queue the move of a directory from `old` to `new`, by moving every file under it.
+    ///
+    /// As for a single file, a file moved into the watched files is indexed as a new one, and
+    /// a file moved out of them is removed.
+    fn move_dir(&self, old: &Path, new: &Path) -> Result<()> {
+        for file in files_under(new)? {
+            let from = old.join(file.strip_prefix(new)?);
+            match (self.watches.includes(&from), self.watches.includes(&file)) {
+                (true, true) => self.enqueue(&file, Operation::Move { from }),
+                (false, true) => self.enqueue(&file, Operation::Index { created: true }),
+                (true, false) => self.enqueue(&from, Operation::Remove),
+                (false, false) => {}
+            }
+        }
         Ok(())
     }
 }
 
+/// Check whether the server rejected a change because it was made on an outdated version.
+fn is_conflict(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<tonic::Status>(), Some(status) if status.code() == Code::Aborted)
+}
+
+/// Recursively list the files under `dir`.
+///
+/// Symbolic links to directories are not followed, as they may point back to `dir`.
+pub fn files_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(files_under(&path)?);
+        } else if file_type.is_symlink() && path.is_dir() {
+            debug!(
+                "skipping symbolic link to directory. path={}",
+                &path.display()
+            );
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
 #[async_trait]
 impl WatcherListener for Indexer {
     async fn on_event(&self, event: &DebouncedEvent) -> Result<()> {
+        // The changes written by the synchronizer are already known by the server
+        if self.watches.is_echo(event) {
+            debug!("ignoring event of a synchronized change. event={:?}", event);
+            return Ok(());
+        }
+
         match event {
             DebouncedEvent::Create(path) => {
                 // We don't want to sync an empty directory.
@@ -92,21 +475,11 @@ impl WatcherListener for Indexer {
                 }
 
                 debug!("new file detected. file={}", &path.display());
-                if let Err(e) = self.index(path.as_path(), FileEventType::from(event)).await {
-                    error!(
-                        "an error occurred when trying to index the file. details={}",
-                        e
-                    )
-                }
+                self.enqueue(path, Operation::Index { created: true });
             }
             DebouncedEvent::Write(path) => {
                 debug!("modification detected. file={}", &path.display());
-                if let Err(e) = self.index(path.as_path(), FileEventType::Update).await {
-                    error!(
-                        "an error occurred when trying to index the file. details={}",
-                        e
-                    )
-                }
+                self.enqueue(path, Operation::Index { created: false });
             }
             DebouncedEvent::Chmod(path) => {
                 debug!("file attributes updated. file={}", &path.display());
@@ -114,21 +487,7 @@ impl WatcherListener for Indexer {
             }
             DebouncedEvent::Remove(path) => {
                 debug!("removing detected. file={}", &path.display());
-                let response = self
-                    .notify(FileEventRequest {
-                        client_name: None,
-                        event_type: FileEventType::Delete.into(),
-                        file: Some(File {
-                            version: None,
-                            path: path.display().to_string(),
-                            base_name: path.file_name().unwrap().to_str().unwrap().to_string(),
-                            created: None,
-                            last_updated: None,
-                        }),
-                    })
-                    .await?;
-                debug!("removed file. file={}", &path.display());
-                println!("{:?}", response);
+                self.enqueue(path, Operation::Remove);
             }
             DebouncedEvent::Rename(old, new) => {
                 debug!(
@@ -136,11 +495,17 @@ impl WatcherListener for Indexer {
                     &old.display(),
                     &new.display()
                 );
-                warn!("behavior not implemented");
+                if !new.is_dir() {
+                    self.enqueue(new, Operation::Move { from: old.clone() });
+                } else if let Err(e) = self.move_dir(old, new) {
+                    error!(
+                        "an error occurred when trying to index the move. details={}",
+                        e
+                    )
+                }
             }
             DebouncedEvent::Rescan => {
-                warn!("a problem has been detected that makes it necessary to re-scan the watched directories.");
-                warn!("behavior not implemented");
+                debug!("re-scan of the watched directories is handled by the reconciler");
             }
             DebouncedEvent::Error(e, path) => {
                 if let Some(path) = path {
@@ -160,3 +525,125 @@ impl WatcherListener for Indexer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::indexer::{files_under, is_conflict};
+    use crate::queue::{Operation, Queue};
+    use crate::test_utils::indexer;
+    use crate::watcher::WatcherListener;
+    use notify::DebouncedEvent;
+    use std::fs::{copy, create_dir_all, rename, write, File};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    /// Get the pending operations, by path.
+    fn pending(queue: &Queue) -> Vec<(PathBuf, Operation)> {
+        queue
+            .pending()
+            .expect("failed to list operations")
+            .into_iter()
+            .map(|pending| (pending.path, pending.operation))
+            .collect()
+    }
+
+    #[test]
+    fn test_it_list_files_under_directory_recursively() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        create_dir_all(tmp.path().join("a/b")).expect("failed to create directories");
+        File::create(tmp.path().join("a/one.txt")).expect("failed to create file");
+        File::create(tmp.path().join("a/b/two.txt")).expect("failed to create file");
+
+        let mut files = files_under(tmp.path()).expect("failed to list files");
+        files.sort();
+
+        assert_eq!(
+            files,
+            vec![tmp.path().join("a/b/two.txt"), tmp.path().join("a/one.txt")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_it_skip_symbolic_links_to_directories() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        create_dir_all(tmp.path().join("a")).expect("failed to create directories");
+        File::create(tmp.path().join("a/one.txt")).expect("failed to create file");
+        // A link to a parent directory would be listed endlessly
+        std::os::unix::fs::symlink(tmp.path(), tmp.path().join("a/loop"))
+            .expect("failed to create link");
+
+        let files = files_under(tmp.path()).expect("failed to list files");
+
+        assert_eq!(files, vec![tmp.path().join("a/one.txt")]);
+    }
+
+    #[tokio::test]
+    async fn test_it_index_directories_moved_in_and_out_of_the_watched_files() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let (indexer, queue) = indexer(tmp.path()).await;
+        let (inside, outside) = (tmp.path().join("docs/a"), tmp.path().join("outside"));
+        create_dir_all(&outside).expect("failed to create directory");
+        File::create(outside.join("one.txt")).expect("failed to create file");
+
+        rename(&outside, &inside).expect("failed to move directory");
+        indexer
+            .on_event(&DebouncedEvent::Rename(outside.clone(), inside.clone()))
+            .await
+            .expect("failed to handle event");
+        assert_eq!(
+            pending(&queue),
+            vec![(inside.join("one.txt"), Operation::Index { created: true })]
+        );
+
+        rename(&inside, &outside).expect("failed to move directory");
+        indexer
+            .on_event(&DebouncedEvent::Rename(inside.clone(), outside))
+            .await
+            .expect("failed to handle event");
+        assert_eq!(
+            pending(&queue),
+            vec![(inside.join("one.txt"), Operation::Remove)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_it_index_conflict_copy_as_a_new_file() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let (indexer, queue) = indexer(tmp.path()).await;
+        let local = tmp.path().join("docs/report.txt");
+        let conflict = tmp
+            .path()
+            .join("docs/report (conflict from host-b 2022-04-01).txt");
+        write(&local, "local changes").expect("failed to write file");
+
+        // The synchronizer keeps a copy, then downloads the remote version in place
+        copy(&local, &conflict).expect("failed to copy file");
+        write(&local, "remote changes").expect("failed to write file");
+        let hash = crate::state::hash(&local).expect("failed to hash file");
+        indexer.watches.expect_change(&local, Some(&hash));
+
+        for event in [
+            DebouncedEvent::Create(conflict.clone()),
+            DebouncedEvent::Write(local),
+        ] {
+            indexer
+                .on_event(&event)
+                .await
+                .expect("failed to handle event");
+        }
+        assert_eq!(
+            pending(&queue),
+            vec![(conflict, Operation::Index { created: true })]
+        );
+    }
+
+    #[test]
+    fn test_it_detect_changes_rejected_as_conflicts() {
+        assert!(is_conflict(&tonic::Status::aborted("conflict").into()));
+        assert!(!is_conflict(
+            &tonic::Status::unavailable("unreachable").into()
+        ));
+        assert!(!is_conflict(&anyhow::anyhow!("failed to open file")));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::config::StabilityConfig;
+use crate::state::metadata;
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Minimum delay before checking again a file which is not settled.
+const MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// The last size and modification time seen for a file which is not settled yet.
+#[derive(Debug, Clone)]
+struct Observation {
+    size: u64,
+    mtime: u64,
+    /// When the size or modification time last changed
+    changed: Instant,
+    /// When the file was first seen unsettled
+    first: Instant,
+}
+
+/// `Stability` tells whether a file is settled, i.e it is no longer being written, so it can
+/// be uploaded without sending a partial content.
+///
+/// A file is settled once its size and modification time did not change for the configured
+/// settle time and, if enabled, no process holds it open for writing.
+#[derive(Debug, Clone)]
+pub struct Stability {
+    settle_time: Duration,
+    max_wait: Duration,
+    check_writers: bool,
+    observations: Arc<Mutex<HashMap<PathBuf, Observation>>>,
+}
+
+impl Stability {
+    pub fn new(config: &StabilityConfig) -> Self {
+        Self {
+            settle_time: Duration::from_secs(config.settle_time),
+            max_wait: Duration::from_secs(config.max_wait),
+            check_writers: config.check_writers,
+            observations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Check whether a file is settled, and return the delay before it should be checked again
+    /// if it is not.
+    ///
+    /// A file which cannot be read is considered settled, so the caller handles it as usual.
+    pub fn wait(&self, path: &Path) -> Option<Duration> {
+        let mut observations = self.observations.lock().unwrap();
+        let (size, mtime) = match metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                observations.remove(path);
+                return None;
+            }
+        };
+
+        let now = Instant::now();
+        let observation = observations
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Observation {
+                size,
+                mtime,
+                // The file last changed when it was last modified
+                changed: now.checked_sub(age(mtime)).unwrap_or(now),
+                first: now,
+            });
+        if observation.size != size || observation.mtime != mtime {
+            observation.size = size;
+            observation.mtime = mtime;
+            observation.changed = now;
+        }
+
+        if observation.first.elapsed() >= self.max_wait {
+            warn!(
+                "file did not settle in time, uploading it anyway. file={}, waited={}s",
+                &path.display(),
+                observation.first.elapsed().as_secs()
+            );
+            observations.remove(path);
+            return None;
+        }
+
+        let unchanged = observation.changed.elapsed();
+        if unchanged < self.settle_time {
+            return Some((self.settle_time - unchanged).max(MIN_DELAY));
+        }
+        if self.check_writers && is_open_for_writing(path) {
+            return Some(self.settle_time.max(MIN_DELAY));
+        }
+
+        observations.remove(path);
+        None
+    }
+}
+
+/// Get the time elapsed since `mtime`, a modification time in nanoseconds since UNIX epoch.
+fn age(mtime: u64) -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_nanos(mtime))
+        .unwrap_or_default()
+}
+
+/// Check whether a process holds a file open for writing, by looking at the file descriptors
+/// of the processes the daemon can see.
+#[cfg(target_os = "linux")]
+fn is_open_for_writing(path: &Path) -> bool {
+    use std::fs::{read_dir, read_link, read_to_string};
+
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let processes = match read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return false,
+    };
+
+    for process in processes.flatten() {
+        let fds = match read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            // Not a process, or one the daemon cannot inspect
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            if !read_link(fd.path()).is_ok_and(|target| target == path) {
+                continue;
+            }
+            // The access mode is held by the two lowest bits of the octal flags
+            let info = process.path().join("fdinfo").join(fd.file_name());
+            let writes = read_to_string(info).is_ok_and(|info| {
+                info.lines()
+                    .find_map(|line| line.strip_prefix("flags:"))
+                    .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+                    .is_some_and(|flags| flags & 0o3 != 0)
+            });
+            if writes {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_open_for_writing(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::StabilityConfig;
+    use crate::indexer::stability::Stability;
+    use std::fs::{write, File};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_wait_for_files_being_written() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = tmp.path().join("dump.sql");
+        write(&path, "partial").expect("failed to write file");
+
+        let stability = Stability::new(&StabilityConfig {
+            settle_time: 60,
+            ..StabilityConfig::default()
+        });
+        assert!(stability.wait(&path).is_some());
+
+        let settled = Stability::new(&StabilityConfig {
+            settle_time: 0,
+            ..StabilityConfig::default()
+        });
+        assert_eq!(settled.wait(&path), None);
+        assert_eq!(settled.wait(&tmp.path().join("missing.sql")), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_it_detect_files_open_for_writing() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = tmp.path().join("video.mp4");
+        let writer = File::create(&path).expect("failed to create file");
+
+        let stability = Stability::new(&StabilityConfig {
+            settle_time: 0,
+            max_wait: 3600,
+            check_writers: true,
+        });
+        assert!(stability.wait(&path).is_some());
+
+        drop(writer);
+        assert_eq!(stability.wait(&path), None);
+    }
+}
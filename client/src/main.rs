@@ -2,24 +2,50 @@ mod cli;
 mod command;
 mod config;
 mod grpc;
+mod identity;
+mod ignores;
 mod indexer;
+mod queue;
+mod reconciler;
+mod roots;
+mod state;
+mod status;
 mod storage_manager;
 mod synchronizer;
+#[cfg(test)]
+mod test_utils;
 mod watcher;
 
+use crate::cli::absolute;
+use crate::cli::conflicts::ConflictsCommand;
+use crate::cli::get::GetCommand;
 use crate::cli::list::ListCommand;
+use crate::cli::log::LogCommand;
+use crate::cli::output::OutputFormat;
+use crate::cli::restore::RestoreCommand;
+use crate::cli::status::StatusCommand;
+use crate::cli::watch::WatchCommand;
 use crate::command::handler::CommandHandler;
 use crate::command::pipe::{CommandListener, CommandWriter};
 use crate::config::Config;
 use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
+use crate::identity::Identity;
+use crate::ignores::Ignores;
+use crate::indexer::stability::Stability;
 use crate::indexer::Indexer;
+use crate::queue::Queue;
+use crate::reconciler::Reconciler;
+use crate::roots::Roots;
+use crate::state::State;
+use crate::status::Status;
 use crate::synchronizer::Synchronizer;
 use crate::watcher::PoolWatcher;
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 use log::{info, LevelFilter};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 const POLYDRIVE_SOCKET: &str = "/tmp/polydrive.sock";
 
@@ -27,8 +53,8 @@ pub trait Handler {
     /// Executes the command handler.
     ///
     /// Every command should take no argument, has it is built at runtime with these arguments.
-    /// Also, a command must always return a `Result<()>`.
-    fn handler(&self, command_bus: CommandWriter) -> Result<()>;
+    /// Also, a command must always return a `Result<()>`, and print its result in the `output` format.
+    fn handler(&self, command_bus: CommandWriter, output: OutputFormat) -> Result<()>;
 }
 
 #[derive(Parser, Debug)]
@@ -44,11 +70,14 @@ struct Cli {
 
     /// A list of files or directories to watch.
     ///
-    /// Supports glob-based path, e.g: /tmp/**/**.png. If the path is a glob, it'll be expanded, so /tmp/*/**.png will
-    /// detect every png FILE present behind your /tmp folder. Be aware, if you pass a glob path, it will not watch folders,
-    /// but only existing files matching the glob pattern when the command is executed.
+    /// Supports glob-based path, e.g: /tmp/**/*.png. If the path is a glob, the deepest folder of the path which is not
+    /// a glob is watched, and only the files matching the pattern are synchronized, so /tmp/**/*.png will detect every
+    /// png FILE behind your /tmp folder, including the ones created after the daemon started.
     ///
-    /// You can use the client mode to add more watch later.
+    /// You can use the client mode to add more watch later, with `polydrive watch add <PATH>`.
+    ///
+    /// Files are synchronized relative to a sync root: a watched path which is not under a root of the configuration
+    /// gets its own root, named after the deepest directory of the path which is not a glob, e.g `/tmp` for `/tmp/**/*.png`.
     ///
     /// Examples:
     ///
@@ -70,6 +99,14 @@ struct Cli {
     #[clap(short, long)]
     config: Option<PathBuf>,
 
+    /// The format in which the results of the commands are printed.
+    ///
+    /// Example, to get the synchronized files as JSON:
+    ///
+    /// polydrive list --output json
+    #[clap(short, long, global = true, arg_enum, default_value = "table")]
+    output: OutputFormat,
+
     /// The command to execute.
     #[clap(subcommand)]
     command: Option<Command>,
@@ -83,6 +120,12 @@ impl Cli {
         if let Some(command) = self.command {
             return match command {
                 Command::List(cmd) => Ok(Box::new(cmd)),
+                Command::Conflicts(cmd) => Ok(Box::new(cmd)),
+                Command::Get(cmd) => Ok(Box::new(cmd)),
+                Command::Log(cmd) => Ok(Box::new(cmd)),
+                Command::Restore(cmd) => Ok(Box::new(cmd)),
+                Command::Watch(cmd) => Ok(Box::new(cmd)),
+                Command::Status(cmd) => Ok(Box::new(cmd)),
             };
         }
 
@@ -95,6 +138,12 @@ impl Cli {
 #[derive(Subcommand, Debug)]
 pub enum Command {
     List(ListCommand),
+    Conflicts(ConflictsCommand),
+    Get(GetCommand),
+    Log(LogCommand),
+    Restore(RestoreCommand),
+    Watch(WatchCommand),
+    Status(StatusCommand),
 }
 
 #[tokio::main]
@@ -118,28 +167,98 @@ async fn main() -> Result<()> {
         info!("bootstrapping gRPC client");
         let client = FileManagerServiceClient::connect(config.get_server_address()).await?;
 
-        let indexer = Indexer::bootstrap(client.clone()).await?;
+        let identity = Identity::load(&config.data.path)?;
+        let state = State::open(&config.data.path)?;
+        let status = Status::default();
+        // The watch events carry absolute paths, so relative `--watch` and root paths are made
+        // absolute too
+        let files = cli
+            .files
+            .iter()
+            .map(|path| absolute(path))
+            .collect::<Result<Vec<String>>>()?;
+        let configured = config
+            .roots
+            .iter()
+            .map(|(name, path)| {
+                Ok((
+                    name.clone(),
+                    PathBuf::from(absolute(&path.display().to_string())?),
+                ))
+            })
+            .collect::<Result<BTreeMap<String, PathBuf>>>()?;
+        let roots = Roots::new(&configured, &files)?;
+        // Watch the configured roots along with the `--watch` paths
+        let watched = files
+            .iter()
+            .cloned()
+            .chain(configured.values().map(|path| path.display().to_string()))
+            .collect::<Vec<String>>();
+        let ignores = Ignores::new(&config.ignore, roots.clone())?.exclude(&config.data.path);
+        let mut watcher = PoolWatcher::init(&watched, roots.clone(), ignores)?;
 
-        let command_handler = CommandHandler::new(client.clone());
-        // Start the socket listener into a thread
-        // in order to handle agent commands
-        tokio::task::spawn(async move {
-            CommandListener::new(POLYDRIVE_SOCKET, command_handler)?
-                .listen()
-                .await
-        });
+        let synchronizer = Synchronizer::bootstrap(
+            client.clone(),
+            identity.clone(),
+            state.clone(),
+            roots.clone(),
+            watcher.handle(),
+            status.clone(),
+        )
+        .await?;
+
+        let queue = Queue::open(&state)?;
+        let indexer = Indexer::bootstrap(
+            client.clone(),
+            identity.clone(),
+            state.clone(),
+            roots.clone(),
+            watcher.handle(),
+            queue.clone(),
+            status.clone(),
+            Stability::new(&config.stability),
+            synchronizer.clone(),
+        )
+        .await?;
+        // Index the queued file events in another thread
+        let worker = indexer.clone();
+        tokio::task::spawn(async move { worker.run().await });
 
         // Start synchronizer into another thread because
         // PoolWatcher start() method is blocking.
-        tokio::task::spawn(async move {
-            Synchronizer::bootstrap(client.clone())
-                .await?
-                .listen()
-                .await
-        });
-
-        PoolWatcher::init(&cli.files)
-            .add_listener(Arc::new(Mutex::new(indexer.clone())))
+        let listener = synchronizer.clone();
+        tokio::task::spawn(async move { listener.listen().await });
+
+        // Bring the watched files in line with the remote index, as they
+        // may have changed while the daemon was down.
+        let reconciler = Reconciler::new(
+            client.clone(),
+            indexer.clone(),
+            synchronizer,
+            state.clone(),
+            roots.clone(),
+            watcher.handle(),
+        );
+        let startup_reconciler = reconciler.clone();
+        tokio::task::spawn(async move { startup_reconciler.reconcile().await });
+
+        let command_handler = CommandHandler::new(
+            client.clone(),
+            identity.clone(),
+            state,
+            roots,
+            watcher.handle(),
+            reconciler.clone(),
+            queue,
+            status,
+        );
+        // Serve the commands of the CLI on the control socket
+        let command_listener = CommandListener::new(POLYDRIVE_SOCKET, command_handler)?;
+        tokio::task::spawn(async move { command_listener.listen().await });
+
+        watcher
+            .add_listener(Arc::new(indexer.clone()))
+            .add_listener(Arc::new(reconciler))
             .start()
             .await?;
 
@@ -147,5 +266,6 @@ async fn main() -> Result<()> {
     }
 
     let cmd_writer = CommandWriter::new(POLYDRIVE_SOCKET)?;
-    cli.command()?.handler(cmd_writer)
+    let output = cli.output;
+    cli.command()?.handler(cmd_writer, output)
 }
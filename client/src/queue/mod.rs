@@ -0,0 +1,387 @@
+use crate::state::State;
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sled::transaction::ConflictableTransactionError;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// Name of the tree holding the pending operations.
+const QUEUE_TREE: &str = "queue";
+/// Delay before the first retry of a failed operation.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between two attempts of a failed operation.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+/// Number of failed attempts after which an operation is parked.
+const MAX_ATTEMPTS: u32 = 20;
+
+/// An operation to index on the remote server.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Operation {
+    /// Index the content of a file, `created` is set if the file is new
+    Index { created: bool },
+    /// Index the deletion of a file
+    Remove,
+    /// Index the move of a file from another path, along with its content
+    Move { from: PathBuf },
+}
+
+/// An operation waiting to be indexed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Pending {
+    /// The local path of the file
+    pub path: PathBuf,
+    /// The operation to index
+    pub operation: Operation,
+    /// The number of failed attempts
+    pub attempts: u32,
+    /// When the operation can be attempted, in milliseconds since UNIX epoch
+    pub next_attempt: u64,
+    /// The error of the last failed attempt
+    pub last_error: Option<String>,
+    /// Whether the operation failed too many times and is no longer attempted, until the
+    /// file changes again
+    #[serde(default)]
+    pub parked: bool,
+    /// The sequence number of the operation, to keep the order in which files changed
+    sequence: u64,
+}
+
+/// The `Queue` holds the operations waiting to be indexed on the remote server.
+///
+/// It is persisted on disk, so pending operations survive restarts, and holds at most one
+/// operation per path: an event on a file which already has a pending operation is merged
+/// into it. Failed operations are retried with an exponential backoff, and parked after
+/// too many attempts.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    state: State,
+    tree: sled::Tree,
+    /// Wakes up the consumer of the queue when an operation is pushed
+    wakeup: Arc<Notify>,
+}
+
+impl Queue {
+    /// Open the queue stored in the state database.
+    pub fn open(state: &State) -> Result<Self> {
+        Ok(Self {
+            state: state.clone(),
+            tree: state.open_tree(QUEUE_TREE)?,
+            wakeup: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Push an operation on a file, merging it with the pending operation of the file, if any.
+    pub fn push(&self, path: &Path, operation: Operation) -> Result<()> {
+        let sequence = self.state.generate_id()?;
+        // The pending operation is read and replaced at once, so a concurrent push or
+        // completion on the same file cannot be lost
+        self.tree
+            .transaction(|tree| {
+                let previous = match tree.get(path.display().to_string())? {
+                    Some(value) => Some(
+                        serde_json::from_slice::<Pending>(&value)
+                            .map_err(ConflictableTransactionError::Abort)?
+                            .operation,
+                    ),
+                    None => None,
+                };
+
+                let (path, operation) = match (previous, operation.clone()) {
+                    // The server only knows the file at the path it was moved from
+                    (Some(Operation::Move { from }), Operation::Remove) => {
+                        tree.remove(path.display().to_string().as_str())?;
+                        (from, Operation::Remove)
+                    }
+                    (previous, operation) => (path.to_path_buf(), merge(previous, operation)),
+                };
+                let key = path.display().to_string();
+                debug!(
+                    "queuing operation. path={}, operation={:?}",
+                    &key, &operation
+                );
+                if let Operation::Move { from } = &operation {
+                    // The file is gone from its old path, along with anything pending on it
+                    tree.remove(from.display().to_string().as_str())?;
+                }
+
+                let pending = Pending {
+                    path,
+                    operation,
+                    attempts: 0,
+                    next_attempt: 0,
+                    last_error: None,
+                    parked: false,
+                    sequence,
+                };
+                tree.insert(
+                    key.as_str(),
+                    serde_json::to_vec(&pending).map_err(ConflictableTransactionError::Abort)?,
+                )?;
+                Ok(())
+            })
+            .map_err(|e| {
+                anyhow!(
+                    "failed to queue operation. path={}, details={:?}",
+                    path.display(),
+                    e
+                )
+            })?;
+        self.tree.flush()?;
+
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    /// Get every pending operation, in the order the files changed.
+    pub fn pending(&self) -> Result<Vec<Pending>> {
+        let mut pending = self
+            .tree
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect::<Result<Vec<Pending>>>()?;
+        pending.sort_by_key(|pending| pending.sequence);
+        Ok(pending)
+    }
+
+    /// Get the operations which can be attempted now, in the order the files changed.
+    pub fn due(&self) -> Result<Vec<Pending>> {
+        let now = now();
+        Ok(self
+            .pending()?
+            .into_iter()
+            .filter(|pending| !pending.parked && pending.next_attempt <= now)
+            .collect())
+    }
+
+    /// Get the number of pending operations.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Remove an operation once it was indexed.
+    ///
+    /// The operation is kept if the file changed meanwhile, as it was merged with a newer one.
+    pub fn done(&self, pending: &Pending) -> Result<()> {
+        self.update(pending, |_| None)
+    }
+
+    /// Schedule a new attempt of a failed operation, and return the delay before it.
+    ///
+    /// Returns `None` if the operation failed too many times: it is then parked, and only
+    /// attempted again once the file changes.
+    pub fn retry(&self, pending: &Pending, error: &str) -> Result<Option<Duration>> {
+        let attempts = pending.attempts + 1;
+        let delay = backoff(pending.attempts);
+        let parked = attempts >= MAX_ATTEMPTS;
+        self.update(pending, |mut pending| {
+            pending.attempts = attempts;
+            pending.next_attempt = now() + delay.as_millis() as u64;
+            pending.last_error = Some(error.to_string());
+            pending.parked = parked;
+            Some(pending)
+        })?;
+
+        if parked {
+            error!(
+                "operation failed too many times, parking it until the file changes. file={}, attempts={}, details={}",
+                &pending.path.display(),
+                attempts,
+                error
+            );
+            return Ok(None);
+        }
+        Ok(Some(delay))
+    }
+
+    /// Delay an operation which cannot be attempted yet, without counting it as a failure.
+    pub fn postpone(&self, pending: &Pending, delay: Duration) -> Result<()> {
+        self.update(pending, |mut pending| {
+            pending.next_attempt = now() + delay.as_millis() as u64;
+            Some(pending)
+        })
+    }
+
+    /// Wait until an operation is pushed, or the next failed operation can be attempted.
+    pub async fn wait(&self) -> Result<()> {
+        let next = self
+            .pending()?
+            .iter()
+            .filter(|pending| !pending.parked)
+            .map(|pending| pending.next_attempt)
+            .min();
+
+        match next {
+            Some(next) => {
+                let delay = Duration::from_millis(next.saturating_sub(now()));
+                let _ = tokio::time::timeout(delay, self.wakeup.notified()).await;
+            }
+            None => self.wakeup.notified().await,
+        }
+        Ok(())
+    }
+
+    /// Update the entry of an operation, unless it was replaced by a newer one.
+    fn update(&self, pending: &Pending, f: impl Fn(Pending) -> Option<Pending>) -> Result<()> {
+        let key = pending.path.display().to_string();
+        self.tree.fetch_and_update(&key, |value| {
+            let current = serde_json::from_slice::<Pending>(value?).ok()?;
+            if current.sequence != pending.sequence {
+                return serde_json::to_vec(&current).ok();
+            }
+            f(current).and_then(|updated| serde_json::to_vec(&updated).ok())
+        })?;
+        self.tree.flush()?;
+        Ok(())
+    }
+}
+
+/// Merge an operation on a file with the operation already pending on it.
+fn merge(previous: Option<Operation>, next: Operation) -> Operation {
+    match (previous, next) {
+        // The file is still new to the server
+        (Some(Operation::Index { created: true }), Operation::Index { .. })
+        | (Some(Operation::Remove), Operation::Index { .. }) => Operation::Index { created: true },
+        // The move is indexed along with the content of the file
+        (Some(Operation::Move { from }), Operation::Index { .. }) => Operation::Move { from },
+        (_, next) => next,
+    }
+}
+
+/// Compute the delay before the next attempt of an operation which failed `attempts` times.
+///
+/// The delay doubles at each attempt, and half of it is random, so clients do not retry all
+/// at once when the server comes back.
+pub fn backoff(attempts: u32) -> Duration {
+    let delay = BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempts))
+        .unwrap_or(MAX_DELAY)
+        .min(MAX_DELAY);
+    delay / 2 + rand::thread_rng().gen_range(Duration::ZERO..=delay / 2)
+}
+
+/// Get the current time, in milliseconds since UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::queue::{backoff, Operation, Queue, MAX_ATTEMPTS, MAX_DELAY};
+    use crate::state::State;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_merge_operations_on_the_same_path() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let queue = Queue::open(&State::open(tmp.path()).expect("failed to open state"))
+            .expect("failed to open queue");
+        let (a, b) = (PathBuf::from("/tmp/a.txt"), PathBuf::from("/tmp/b.txt"));
+
+        queue
+            .push(&a, Operation::Index { created: true })
+            .expect("failed to push");
+        queue
+            .push(&a, Operation::Index { created: false })
+            .expect("failed to push");
+        queue.push(&b, Operation::Remove).expect("failed to push");
+
+        let pending = queue.pending().expect("failed to list operations");
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].operation, Operation::Index { created: true });
+        assert_eq!(pending[1].operation, Operation::Remove);
+
+        queue
+            .push(&b, Operation::Move { from: a.clone() })
+            .expect("failed to push");
+        assert_eq!(queue.len(), 1);
+
+        // A file moved then removed is removed from its old path
+        queue.push(&b, Operation::Remove).expect("failed to push");
+        let pending = queue.pending().expect("failed to list operations");
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].path, a);
+        assert_eq!(pending[0].operation, Operation::Remove);
+    }
+
+    #[test]
+    fn test_it_retry_failed_operations_later_and_persist_them() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = PathBuf::from("/tmp/a.txt");
+        {
+            let queue = Queue::open(&State::open(tmp.path()).expect("failed to open state"))
+                .expect("failed to open queue");
+            queue
+                .push(&path, Operation::Remove)
+                .expect("failed to push");
+
+            let pending = queue.due().expect("failed to list operations");
+            queue
+                .retry(&pending[0], "server unreachable")
+                .expect("failed to retry");
+            assert!(queue.due().expect("failed to list operations").is_empty());
+        }
+
+        let queue = Queue::open(&State::open(tmp.path()).expect("failed to reopen state"))
+            .expect("failed to open queue");
+        let pending = queue.pending().expect("failed to list operations");
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(pending[0].last_error.as_deref(), Some("server unreachable"));
+
+        // A newer event on the file is not dropped by the completion of the older one
+        queue
+            .push(&path, Operation::Index { created: false })
+            .expect("failed to push");
+        queue.done(&pending[0]).expect("failed to complete");
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_it_park_operations_failing_too_many_times() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let queue = Queue::open(&State::open(tmp.path()).expect("failed to open state"))
+            .expect("failed to open queue");
+        let path = PathBuf::from("/tmp/a.txt");
+        queue
+            .push(&path, Operation::Index { created: true })
+            .expect("failed to push");
+
+        for attempts in 1..=MAX_ATTEMPTS {
+            let pending = queue.pending().expect("failed to list operations");
+            let delay = queue
+                .retry(&pending[0], "file rejected")
+                .expect("failed to retry");
+            assert_eq!(delay.is_none(), attempts == MAX_ATTEMPTS);
+        }
+        let pending = queue.pending().expect("failed to list operations");
+        assert!(pending[0].parked);
+        assert!(queue.due().expect("failed to list operations").is_empty());
+
+        // A new change on the file is attempted again
+        queue
+            .push(&path, Operation::Index { created: false })
+            .expect("failed to push");
+        let due = queue.due().expect("failed to list operations");
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 0);
+        assert_eq!(due[0].operation, Operation::Index { created: true });
+    }
+
+    #[test]
+    fn test_it_back_off_exponentially() {
+        assert!(backoff(0) <= Duration::from_secs(1));
+        assert!(backoff(3) >= Duration::from_secs(4));
+        assert!(backoff(3) <= Duration::from_secs(8));
+        assert!(backoff(40) <= MAX_DELAY);
+        assert!(backoff(40) >= MAX_DELAY / 2);
+    }
+}
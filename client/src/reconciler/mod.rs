@@ -0,0 +1,275 @@
+use crate::grpc::file::{File, FileEventType};
+use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
+use crate::indexer::{files_under, Indexer};
+use crate::queue::Operation;
+use crate::roots::Roots;
+use crate::state::State;
+use crate::synchronizer::Synchronizer;
+use crate::watcher::{WatchHandle, WatcherListener};
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use notify::DebouncedEvent;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tonic::transport::Channel;
+
+/// An action required to bring a file back in line with its remote version.
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    /// Upload the local file
+    Upload(PathBuf, FileEventType),
+    /// Apply the remote version of the file, by downloading or deleting it
+    Download(File),
+    /// Delete the remote file, as it was deleted locally
+    Delete(PathBuf),
+}
+
+/// The `Reconciler` is responsible to bring the watched files and the remote index
+/// back in line, when file events may have been missed.
+///
+/// It runs when the daemon starts, as files may have changed while it was down,
+/// and whenever the watcher asks for a re-scan.
+#[derive(Clone)]
+pub struct Reconciler {
+    client: FileManagerServiceClient<Channel>,
+    indexer: Indexer,
+    synchronizer: Synchronizer,
+    state: State,
+    /// The sync roots, to convert remote paths
+    roots: Roots,
+    /// The watched paths
+    watches: WatchHandle,
+}
+
+impl Reconciler {
+    pub fn new(
+        client: FileManagerServiceClient<Channel>,
+        indexer: Indexer,
+        synchronizer: Synchronizer,
+        state: State,
+        roots: Roots,
+        watches: WatchHandle,
+    ) -> Self {
+        Self {
+            client,
+            indexer,
+            synchronizer,
+            state,
+            roots,
+            watches,
+        }
+    }
+
+    /// Diff the watched files with the remote index, and apply the required actions.
+    pub async fn reconcile(&self) -> Result<()> {
+        info!("reconciling watched files with the remote index");
+
+        let local = local_files(&self.watches.paths())
+            .into_iter()
+            .filter(|path| self.watches.includes(path))
+            .collect::<Vec<PathBuf>>();
+        let remote = self
+            .client
+            .clone()
+            .get_files(())
+            .await?
+            .into_inner()
+            .data
+            .into_iter()
+            .filter(|file| {
+                matches!(self.roots.to_local(&file.path), Ok(Some(local)) if self.watches.includes(&local))
+            })
+            .collect::<Vec<File>>();
+
+        let actions = plan(&self.state, &self.roots, &local, &remote)?;
+        info!(
+            "reconciliation requires {} action(s). local={}, remote={}",
+            actions.len(),
+            local.len(),
+            remote.len()
+        );
+
+        for action in actions {
+            if let Err(e) = self.apply(&action).await {
+                error!(
+                    "an error occurred when trying to reconcile a file. action={:?}, details={}",
+                    &action, e
+                )
+            }
+        }
+
+        info!("reconciliation done");
+        Ok(())
+    }
+
+    /// Apply a single action.
+    async fn apply(&self, action: &Action) -> Result<()> {
+        debug!("applying reconciliation action. action={:?}", action);
+        match action {
+            Action::Upload(path, event) => {
+                let created = *event == FileEventType::Create;
+                self.indexer.enqueue(path, Operation::Index { created });
+                Ok(())
+            }
+            Action::Download(file) => self.synchronizer.synchronize(file).await,
+            Action::Delete(path) => {
+                self.indexer.enqueue(path, Operation::Remove);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// List every file under the watched `paths`.
+pub fn local_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_file() {
+            files.push(path.clone());
+            continue;
+        }
+
+        match files_under(path) {
+            Ok(found) => files.extend(found),
+            Err(e) => warn!(
+                "failed to list files under watched path. path={}, details={}",
+                &path.display(),
+                e
+            ),
+        }
+    }
+    files
+}
+
+/// Compute the actions required to bring the `local` files and the `remote` files in line,
+/// according to what the client knows from its `state`.
+fn plan(state: &State, roots: &Roots, local: &[PathBuf], remote: &[File]) -> Result<Vec<Action>> {
+    let mut actions = vec![];
+    let mut local_only = local
+        .iter()
+        .filter_map(|path| Some((roots.to_remote(path).ok()?, path.clone())))
+        .collect::<BTreeMap<String, PathBuf>>();
+
+    for file in remote {
+        let exists = local_only.remove(&file.path).is_some();
+        let path = match roots.to_local(&file.path)? {
+            Some(path) => path,
+            None => continue,
+        };
+        let known = state.get(&file.path)?;
+
+        // A newer remote version always wins, the synchronizer keeps a conflict copy
+        // if the local file changed meanwhile.
+        if file.version.unwrap_or(1) > known.as_ref().map(|k| k.version).unwrap_or(0) {
+            actions.push(Action::Download(file.clone()));
+        } else if file.deleted {
+            if exists {
+                actions.push(Action::Upload(path, FileEventType::Create));
+            }
+        } else if exists {
+            if state.is_modified(&file.path, &path)? {
+                actions.push(Action::Upload(path, FileEventType::Update));
+            }
+        } else if known.map(|k| !k.deleted).unwrap_or(false) {
+            actions.push(Action::Delete(path));
+        }
+    }
+
+    for path in local_only.into_values() {
+        actions.push(Action::Upload(path, FileEventType::Create));
+    }
+
+    Ok(actions)
+}
+
+#[async_trait]
+impl WatcherListener for Reconciler {
+    async fn on_event(&self, event: &DebouncedEvent) -> Result<()> {
+        if let DebouncedEvent::Rescan = event {
+            warn!("a problem has been detected that makes it necessary to re-scan the watched directories.");
+            if let Err(e) = self.reconcile().await {
+                error!("an error occurred during the re-scan. details={}", e)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::file::{File, FileEventType};
+    use crate::reconciler::{plan, Action};
+    use crate::roots::Roots;
+    use crate::state::State;
+    use std::fs::write;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn remote(path: &Path, version: i32, deleted: bool) -> File {
+        File {
+            base_name: String::new(),
+            path: format!("tmp/{}", path.file_name().unwrap().to_string_lossy()),
+            version: Some(version),
+            last_updated: None,
+            created: None,
+            deleted,
+            hash: String::new(),
+            size: 0,
+            device: None,
+        }
+    }
+
+    #[test]
+    fn test_it_plan_reconciliation_actions() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let state = State::open(&tmp.path().join("data")).expect("failed to open state");
+        let roots = Roots::default();
+        roots.add("tmp", tmp.path()).expect("failed to add root");
+        let (synced, modified, newer, removed, created) = (
+            tmp.path().join("synced.txt"),
+            tmp.path().join("modified.txt"),
+            tmp.path().join("newer.txt"),
+            tmp.path().join("removed.txt"),
+            tmp.path().join("created.txt"),
+        );
+
+        for path in [&synced, &modified, &newer, &created] {
+            write(path, "content").expect("failed to write file");
+        }
+        for path in [&synced, &modified, &newer, &removed] {
+            state
+                .track(&roots.to_remote(path).unwrap(), 1, false, path)
+                .expect("failed to set state");
+        }
+        write(&modified, "modified content").expect("failed to write file");
+
+        let actions = plan(
+            &state,
+            &roots,
+            &[
+                synced.clone(),
+                modified.clone(),
+                newer.clone(),
+                created.clone(),
+            ],
+            &[
+                remote(&synced, 1, false),
+                remote(&modified, 1, false),
+                remote(&newer, 2, false),
+                remote(&removed, 1, false),
+            ],
+        )
+        .expect("failed to plan actions");
+
+        assert_eq!(
+            actions,
+            vec![
+                Action::Upload(modified, FileEventType::Update),
+                Action::Download(remote(&newer, 2, false)),
+                Action::Delete(removed),
+                Action::Upload(created, FileEventType::Create),
+            ]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Characters that make a path segment a glob pattern.
+const GLOB_CHARS: [char; 4] = ['*', '?', '[', '{'];
+
+/// A sync root maps a local directory to a remote namespace.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Root {
+    /// The name of the root, used as remote namespace
+    pub name: String,
+    /// The local directory
+    pub path: PathBuf,
+}
+
+/// `Roots` holds the sync roots of the client.
+///
+/// Paths sent to the server are relative to a root and prefixed by its name, e.g the local
+/// file `/home/alice/docs/a.txt` in the root `docs` located at `/home/alice/docs` is known
+/// as `docs/a.txt` on the server, whatever the host it is synchronized on.
+#[derive(Debug, Default, Clone)]
+pub struct Roots {
+    roots: Arc<RwLock<Vec<Root>>>,
+}
+
+impl Roots {
+    /// Build the roots from the named roots of the configuration, and the `--watch` paths.
+    ///
+    /// A watched path which is not under a configured root gets its own root, named after the
+    /// directory holding it, e.g `--watch /data/**/*.png` gives the root `data` located at `/data`.
+    pub fn new(configured: &BTreeMap<String, PathBuf>, watched: &[String]) -> Result<Self> {
+        let roots = Self::default();
+        for (name, path) in configured {
+            roots.add(name, path)?;
+        }
+
+        for pattern in watched {
+            roots.watch(pattern)?;
+        }
+
+        Ok(roots)
+    }
+
+    /// Add a root for a watched path, unless it is already under a root.
+    pub fn watch(&self, pattern: &str) -> Result<()> {
+        let dir = base_dir(pattern);
+        if self.find(&dir).is_some() {
+            return Ok(());
+        }
+        let name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("root"));
+        self.add(&name, &dir)
+    }
+
+    /// Add a root.
+    pub fn add(&self, name: &str, path: &Path) -> Result<()> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(anyhow!("invalid root name. name={}", name));
+        }
+
+        let mut roots = self.roots.write().unwrap();
+        if roots.iter().any(|root| root.name == name) {
+            return Err(anyhow!(
+                "a root with the same name already exists, please name roots in the configuration. name={}, path={}",
+                name,
+                path.display()
+            ));
+        }
+
+        info!("adding sync root. name={}, path={}", name, path.display());
+        roots.push(Root {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    /// Remove the root located at `path`, if any.
+    pub fn remove(&self, path: &Path) -> Option<Root> {
+        let mut roots = self.roots.write().unwrap();
+        let index = roots.iter().position(|root| root.path == path)?;
+        let root = roots.remove(index);
+        info!(
+            "removing sync root. name={}, path={}",
+            &root.name,
+            path.display()
+        );
+        Some(root)
+    }
+
+    /// Get every root.
+    pub fn all(&self) -> Vec<Root> {
+        self.roots.read().unwrap().clone()
+    }
+
+    /// Find the root holding a local path, i.e the deepest root containing it.
+    pub fn find(&self, local: &Path) -> Option<Root> {
+        self.roots
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|root| local.starts_with(&root.path))
+            .max_by_key(|root| root.path.components().count())
+            .cloned()
+    }
+
+    /// Convert a local path to its remote path.
+    pub fn to_remote(&self, local: &Path) -> Result<String> {
+        let root = self
+            .find(local)
+            .ok_or_else(|| anyhow!("path is not under any sync root. path={}", local.display()))?;
+
+        let mut remote = vec![root.name];
+        for component in local.strip_prefix(&root.path)?.components() {
+            match component {
+                Component::Normal(segment) => remote.push(segment.to_string_lossy().to_string()),
+                _ => return Err(anyhow!("invalid local path. path={}", local.display())),
+            }
+        }
+
+        let remote = remote.join("/");
+        debug!(
+            "resolved remote path. local={}, remote={}",
+            local.display(),
+            &remote
+        );
+        Ok(remote)
+    }
+
+    /// Convert a remote path to its local path, under the root it belongs to.
+    ///
+    /// Returns `None` if the root of the path is not synchronized on this device. The path is
+    /// rejected if it could resolve outside of its root: absolute paths, `.` and `..` components,
+    /// and paths crossing a symbolic link which leads out of the root.
+    pub fn to_local(&self, remote: &str) -> Result<Option<PathBuf>> {
+        if remote.starts_with('/') || remote.contains('\\') || remote.contains('\0') {
+            return Err(reject(remote, "absolute path or forbidden character"));
+        }
+
+        let (name, relative) = remote.split_once('/').unwrap_or((remote, ""));
+        let root = match self
+            .roots
+            .read()
+            .unwrap()
+            .iter()
+            .find(|root| root.name == name)
+        {
+            Some(root) => root.clone(),
+            None => return Ok(None),
+        };
+
+        let mut local = root.path.clone();
+        for segment in relative.split('/') {
+            // Each segment must be a single regular component, e.g not `..`, `.` or `C:`
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(component)), None) if component == segment => {
+                    local.push(segment)
+                }
+                _ => return Err(reject(remote, "invalid path component")),
+            }
+        }
+
+        self.check(&local)
+            .map_err(|e| reject(remote, &e.to_string()))?;
+        Ok(Some(local))
+    }
+
+    /// Check that a local path does not resolve outside of the root holding it.
+    ///
+    /// As the path may not exist yet, its deepest existing ancestor is resolved, so
+    /// a symbolic link to a directory outside of the root cannot be used to write there.
+    pub fn check(&self, local: &Path) -> Result<()> {
+        let root = self
+            .find(local)
+            .ok_or_else(|| anyhow!("path is not under any sync root. path={}", local.display()))?;
+        let root_path = root.path.canonicalize().unwrap_or(root.path.clone());
+
+        let mut ancestor = local.parent();
+        while let Some(dir) = ancestor.filter(|dir| dir.starts_with(&root.path)) {
+            if dir.exists() {
+                if !dir.canonicalize()?.starts_with(&root_path) {
+                    return Err(anyhow!(
+                        "path resolves outside of its root. path={}, root={}",
+                        local.display(),
+                        root_path.display()
+                    ));
+                }
+                break;
+            }
+            ancestor = dir.parent();
+        }
+
+        Ok(())
+    }
+}
+
+/// Log the rejection of a remote path as a security event, and build the error.
+fn reject(remote: &str, reason: &str) -> anyhow::Error {
+    warn!(
+        target: "security",
+        "rejected remote path. path={:?}, reason={}", remote, reason
+    );
+    anyhow!("rejected remote path. path={:?}, reason={}", remote, reason)
+}
+
+/// Check whether a path is a glob pattern.
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(&GLOB_CHARS[..])
+}
+
+/// Get the deepest directory of a path pattern which is not a glob.
+///
+/// If the pattern is the path to an existing file, its parent directory is returned.
+pub fn base_dir(pattern: &str) -> PathBuf {
+    let mut dir = PathBuf::new();
+    for component in Path::new(pattern).components() {
+        if is_glob(&component.as_os_str().to_string_lossy()) {
+            return dir;
+        }
+        dir.push(component);
+    }
+
+    if dir.is_file() {
+        if let Some(parent) = dir.parent() {
+            return parent.to_path_buf();
+        }
+    }
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roots::{base_dir, Roots};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_get_base_dir_of_patterns() {
+        assert_eq!(base_dir("/data/**/*.png"), PathBuf::from("/data"));
+        assert_eq!(base_dir("/data/a/*.png"), PathBuf::from("/data/a"));
+        assert_eq!(base_dir("/data/a"), PathBuf::from("/data/a"));
+    }
+
+    #[test]
+    fn test_it_convert_paths_between_local_and_remote() {
+        let configured =
+            BTreeMap::from([(String::from("docs"), PathBuf::from("/home/alice/documents"))]);
+        let roots = Roots::new(&configured, &[String::from("/data/**/*.png")])
+            .expect("failed to build roots");
+
+        assert_eq!(
+            roots
+                .to_remote(&PathBuf::from("/home/alice/documents/a/b.txt"))
+                .unwrap(),
+            "docs/a/b.txt"
+        );
+        assert_eq!(
+            roots.to_remote(&PathBuf::from("/data/x/y.png")).unwrap(),
+            "data/x/y.png"
+        );
+        assert!(roots.to_remote(&PathBuf::from("/etc/passwd")).is_err());
+
+        assert_eq!(
+            roots.to_local("docs/a/b.txt").unwrap(),
+            Some(PathBuf::from("/home/alice/documents/a/b.txt"))
+        );
+        assert_eq!(roots.to_local("unknown/a.txt").unwrap(), None);
+    }
+
+    #[test]
+    fn test_it_reject_remote_paths_outside_of_roots() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let roots = Roots::default();
+        roots.add("docs", tmp.path()).expect("failed to add root");
+
+        for remote in [
+            "/etc/passwd",
+            "docs/../../etc/passwd",
+            "docs/a/../../b",
+            "docs/./a",
+            "docs//a",
+            "docs/",
+        ] {
+            assert!(roots.to_local(remote).is_err(), "{} was accepted", remote);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_it_reject_remote_paths_escaping_through_symlinks() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let outside = tempdir().expect("failed to create temporary directory");
+        std::os::unix::fs::symlink(outside.path(), tmp.path().join("link"))
+            .expect("failed to create symlink");
+        let roots = Roots::default();
+        roots.add("docs", tmp.path()).expect("failed to add root");
+
+        assert!(roots.to_local("docs/link/authorized_keys").is_err());
+        assert!(roots.to_local("docs/dir/a.txt").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_it_reject_duplicate_root_names() {
+        assert!(Roots::new(
+            &BTreeMap::new(),
+            &[String::from("/a/docs"), String::from("/b/docs")]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_it_add_and_remove_roots_of_watched_paths() {
+        let roots = Roots::default();
+        roots.watch("/data/**/*.png").expect("failed to add root");
+        roots.watch("/data/a/*.jpg").expect("failed to add root");
+
+        assert_eq!(
+            roots.to_remote(&PathBuf::from("/data/a/b.jpg")).unwrap(),
+            "data/a/b.jpg"
+        );
+        assert!(roots.remove(&PathBuf::from("/data")).is_some());
+        assert_eq!(roots.to_local("data/a.png").unwrap(), None);
+    }
+}
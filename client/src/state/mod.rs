@@ -0,0 +1,322 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::copy;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Name of the tree holding the state of the files.
+const FILES_TREE: &str = "files";
+/// Name of the tree holding the conflicts.
+const CONFLICTS_TREE: &str = "conflicts";
+/// Name of the tree holding the cached hashes of the local files.
+const HASHES_TREE: &str = "hashes";
+
+/// What the client knows about a synchronized file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileState {
+    /// The last remote version known by the client
+    pub version: i32,
+    /// Whether the last known version is a tombstone
+    pub deleted: bool,
+    /// The size of the local file when it was last synchronized
+    pub size: u64,
+    /// The modification time of the local file when it was last synchronized,
+    /// in nanoseconds since UNIX epoch
+    pub mtime: u64,
+    /// The BLAKE3 hash of the local file content when it was last synchronized
+    pub hash: Option<String>,
+}
+
+impl FileState {
+    /// Build the state of a file from its version and the local file at `path`.
+    fn new(version: i32, deleted: bool, path: &Path, hash: Option<String>) -> Self {
+        let (size, mtime) = metadata(path).unwrap_or_default();
+        Self {
+            version,
+            deleted,
+            size,
+            mtime,
+            hash,
+        }
+    }
+}
+
+/// A hash cached for a local file, valid as long as the file size and modification time
+/// did not change.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct CachedHash {
+    size: u64,
+    mtime: u64,
+    hash: String,
+}
+
+/// A conflict detected between a local change and a remote version of a file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Conflict {
+    /// The path of the file in conflict
+    pub path: String,
+    /// The path of the copy in which the local changes were kept
+    pub copy: String,
+    /// The remote version which caused the conflict
+    pub version: i32,
+    /// When the conflict was detected
+    pub detected: DateTime<Local>,
+}
+
+/// The `State` holds the state of the synchronized files, indexed by their remote path.
+///
+/// It is persisted on disk, so the client remembers what it synchronized between restarts.
+/// It is shared between the `Indexer`, the `Synchronizer` and the `CommandHandler`.
+#[derive(Debug, Clone)]
+pub struct State {
+    db: sled::Db,
+    files: sled::Tree,
+    conflicts: sled::Tree,
+    hashes: sled::Tree,
+}
+
+impl State {
+    /// Open the state database stored in the `dir` directory, creating it if needed.
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join("state");
+        info!("opening local state. path={}", &path.display());
+
+        let db = sled::open(&path).map_err(|e| {
+            anyhow!(
+                "failed to open local state. path={}, details={}",
+                &path.display(),
+                e
+            )
+        })?;
+        let files = db.open_tree(FILES_TREE)?;
+        let conflicts = db.open_tree(CONFLICTS_TREE)?;
+        let hashes = db.open_tree(HASHES_TREE)?;
+
+        Ok(Self {
+            db,
+            files,
+            conflicts,
+            hashes,
+        })
+    }
+
+    /// Open a tree of the state database, for components storing their own data.
+    pub fn open_tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// Generate an identifier, unique and increasing across restarts.
+    pub fn generate_id(&self) -> Result<u64> {
+        Ok(self.db.generate_id()?)
+    }
+
+    /// Get the state of a file, if known.
+    pub fn get(&self, path: &str) -> Result<Option<FileState>> {
+        match self.files.get(path)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the last remote version known for a file, or `0` if the file is unknown.
+    pub fn version(&self, path: &str) -> Result<i32> {
+        Ok(self.get(path)?.map(|state| state.version).unwrap_or(0))
+    }
+
+    /// Set the state of a file.
+    pub fn set(&self, path: &str, state: FileState) -> Result<()> {
+        debug!("updating file state. path={}, state={:?}", path, &state);
+        self.files.insert(path, serde_json::to_vec(&state)?)?;
+        self.files.flush()?;
+        Ok(())
+    }
+
+    /// Set the state of a file from its version and the local file at `local`.
+    pub fn track(&self, path: &str, version: i32, deleted: bool, local: &Path) -> Result<()> {
+        let hash = if deleted { None } else { self.hash(local).ok() };
+        self.set(path, FileState::new(version, deleted, local, hash))
+    }
+
+    /// Get the BLAKE3 hash of a local file.
+    ///
+    /// Hashes are cached by inode, and only computed again when the size or the modification
+    /// time of the file changed.
+    pub fn hash(&self, path: &Path) -> Result<String> {
+        let (size, mtime) = metadata(path)?;
+        let key = file_id(path)?;
+
+        if let Some(value) = self.hashes.get(&key)? {
+            let cached: CachedHash = serde_json::from_slice(&value)?;
+            if cached.size == size && cached.mtime == mtime {
+                return Ok(cached.hash);
+            }
+        }
+
+        debug!("computing file hash. path={}", &path.display());
+        let hash = hash(path)?;
+        self.hashes.insert(
+            key,
+            serde_json::to_vec(&CachedHash {
+                size,
+                mtime,
+                hash: hash.clone(),
+            })?,
+        )?;
+        Ok(hash)
+    }
+
+    /// Check whether the content of the local file at `path` is the same as `hash`.
+    pub fn has_content(&self, path: &Path, hash: &str) -> bool {
+        !hash.is_empty() && path.is_file() && self.hash(path).map(|h| h == hash).unwrap_or(false)
+    }
+
+    /// Remove the state of a file, returning it if any.
+    pub fn remove(&self, path: &str) -> Result<Option<FileState>> {
+        let previous = match self.files.remove(path)? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        };
+        self.files.flush()?;
+        Ok(previous)
+    }
+
+    /// Check whether the local file at `local` changed since the file at `path` was last synchronized.
+    ///
+    /// A file the client has no state for is considered as changed, as its content
+    /// cannot be matched against any known version. A file which was only touched is not.
+    pub fn is_modified(&self, path: &str, local: &Path) -> Result<bool> {
+        let metadata = match metadata(local) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+
+        Ok(match self.get(path)? {
+            Some(state) if state.deleted => true,
+            Some(state) if (state.size, state.mtime) == metadata => false,
+            // The file was touched, but its content may be the same
+            Some(FileState {
+                hash: Some(hash), ..
+            }) => !self.has_content(local, &hash),
+            _ => true,
+        })
+    }
+
+    /// Record a conflict.
+    pub fn add_conflict(&self, conflict: Conflict) -> Result<()> {
+        debug!("recording conflict. conflict={:?}", &conflict);
+        let id = self.generate_id()?;
+        self.conflicts
+            .insert(id.to_be_bytes(), serde_json::to_vec(&conflict)?)?;
+        self.conflicts.flush()?;
+        Ok(())
+    }
+
+    /// Get the conflicts detected by the client, from the oldest to the newest.
+    pub fn conflicts(&self) -> Result<Vec<Conflict>> {
+        self.conflicts
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+}
+
+/// Get the size and the modification time, in nanoseconds since UNIX epoch, of a local file.
+pub fn metadata(path: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = std::fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    Ok((metadata.len(), mtime))
+}
+
+/// Get an identifier of a local file which does not change when the file is modified.
+#[cfg(unix)]
+fn file_id(path: &Path) -> std::io::Result<String> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path)?;
+    Ok(format!("{}:{}", metadata.dev(), metadata.ino()))
+}
+
+/// Get an identifier of a local file which does not change when the file is modified.
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> std::io::Result<String> {
+    Ok(path.display().to_string())
+}
+
+/// Compute the BLAKE3 hash of a local file, as an hexadecimal string.
+pub fn hash(path: &Path) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{FileState, State};
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_detect_local_modifications() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = tmp.path().join("a.txt");
+        let key = path.display().to_string();
+        let state = State::open(tmp.path()).expect("failed to open state");
+
+        assert!(!state.is_modified(&key, &path).unwrap());
+
+        write(&path, "v1").expect("failed to write file");
+        assert!(state.is_modified(&key, &path).unwrap());
+
+        state
+            .track(&key, 1, false, &path)
+            .expect("failed to set state");
+        assert!(!state.is_modified(&key, &path).unwrap());
+
+        write(&path, "version 2").expect("failed to write file");
+        assert!(state.is_modified(&key, &path).unwrap());
+    }
+
+    #[test]
+    fn test_it_persist_state_between_restarts() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        {
+            let state = State::open(tmp.path()).expect("failed to open state");
+            state
+                .set(
+                    "/tmp/a.txt",
+                    FileState {
+                        version: 2,
+                        ..FileState::default()
+                    },
+                )
+                .expect("failed to set state");
+        }
+
+        let state = State::open(tmp.path()).expect("failed to reopen state");
+        assert_eq!(state.version("/tmp/a.txt").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_it_compute_hash_again_when_file_changes() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = tmp.path().join("a.txt");
+        let state = State::open(&tmp.path().join("data")).expect("failed to open state");
+
+        write(&path, "v1").expect("failed to write file");
+        let first = state.hash(&path).expect("failed to hash file");
+        assert_eq!(first, blake3::hash(b"v1").to_hex().to_string());
+        assert!(state.has_content(&path, &first));
+
+        write(&path, "version 2").expect("failed to write file");
+        let second = state.hash(&path).expect("failed to hash file");
+        assert_eq!(second, blake3::hash(b"version 2").to_hex().to_string());
+        assert!(!state.has_content(&path, &first));
+    }
+}
@@ -0,0 +1,235 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// The state of the connection to the notification stream of the server.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Connection {
+    /// The client is subscribing to the notifications
+    Connecting,
+    /// The client receives the notifications
+    Connected { since: DateTime<Local> },
+    /// The client lost the connection, and subscribes again at `retry`
+    Disconnected {
+        since: DateTime<Local>,
+        error: String,
+        retry: DateTime<Local>,
+    },
+}
+
+impl Display for Connection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Connection::Connecting => write!(f, "connecting"),
+            Connection::Connected { since } => {
+                write!(f, "connected since {}", since.format("%Y-%m-%d %H:%M:%S"))
+            }
+            Connection::Disconnected {
+                since,
+                error,
+                retry,
+            } => write!(
+                f,
+                "disconnected since {}, retrying at {} ({})",
+                since.format("%Y-%m-%d %H:%M:%S"),
+                retry.format("%H:%M:%S"),
+                error
+            ),
+        }
+    }
+}
+
+/// The direction of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Upload => write!(f, "upload"),
+            Direction::Download => write!(f, "download"),
+        }
+    }
+}
+
+/// A transfer in progress.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Transfer {
+    pub direction: Direction,
+    /// The path of the file transferred
+    pub path: String,
+    /// The size of the file, if known
+    pub size: Option<u64>,
+    /// The number of bytes transferred so far
+    pub transferred: u64,
+    /// When the transfer started
+    pub started: DateTime<Local>,
+}
+
+/// An error which occurred while synchronizing a file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LastError {
+    pub message: String,
+    pub time: DateTime<Local>,
+}
+
+/// The `Status` holds the live state of the daemon, published by its components and
+/// reported by the `status` command.
+#[derive(Debug, Clone)]
+pub struct Status {
+    connection: Arc<RwLock<Connection>>,
+    /// The transfers in progress, by identifier
+    transfers: Arc<RwLock<BTreeMap<u64, Transfer>>>,
+    next_transfer: Arc<AtomicU64>,
+    last_error: Arc<RwLock<Option<LastError>>>,
+    last_sync: Arc<RwLock<Option<DateTime<Local>>>>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            connection: Arc::new(RwLock::new(Connection::Connecting)),
+            transfers: Arc::new(RwLock::new(BTreeMap::new())),
+            next_transfer: Arc::new(AtomicU64::new(0)),
+            last_error: Arc::new(RwLock::new(None)),
+            last_sync: Arc::new(RwLock::new(None)),
+        }
+    }
+}
+
+impl Status {
+    /// Get the state of the connection to the server.
+    pub fn connection(&self) -> Connection {
+        self.connection.read().unwrap().clone()
+    }
+
+    /// Set the state of the connection to the server.
+    pub fn set_connection(&self, connection: Connection) {
+        *self.connection.write().unwrap() = connection;
+    }
+
+    /// Get the transfers in progress, from the oldest to the newest.
+    pub fn transfers(&self) -> Vec<Transfer> {
+        self.transfers.read().unwrap().values().cloned().collect()
+    }
+
+    /// Start tracking a transfer. It is tracked until the returned `TransferProgress` is dropped.
+    pub fn start_transfer(
+        &self,
+        direction: Direction,
+        path: &str,
+        size: Option<u64>,
+    ) -> TransferProgress {
+        let id = self.next_transfer.fetch_add(1, Ordering::Relaxed);
+        self.transfers.write().unwrap().insert(
+            id,
+            Transfer {
+                direction,
+                path: path.to_string(),
+                size,
+                transferred: 0,
+                started: Local::now(),
+            },
+        );
+        TransferProgress {
+            id,
+            status: self.clone(),
+        }
+    }
+
+    /// Get the last error which occurred while synchronizing a file.
+    pub fn last_error(&self) -> Option<LastError> {
+        self.last_error.read().unwrap().clone()
+    }
+
+    /// Record an error which occurred while synchronizing a file.
+    pub fn record_error(&self, message: &str) {
+        *self.last_error.write().unwrap() = Some(LastError {
+            message: message.to_string(),
+            time: Local::now(),
+        });
+    }
+
+    /// Get the time of the last successful synchronization.
+    pub fn last_sync(&self) -> Option<DateTime<Local>> {
+        *self.last_sync.read().unwrap()
+    }
+
+    /// Record a successful synchronization.
+    pub fn record_sync(&self) {
+        *self.last_sync.write().unwrap() = Some(Local::now());
+    }
+}
+
+/// `TransferProgress` publishes the progress of a transfer, and removes it from the
+/// status once dropped, whether the transfer succeeded or not.
+#[derive(Debug)]
+pub struct TransferProgress {
+    id: u64,
+    status: Status,
+}
+
+impl TransferProgress {
+    /// Add `bytes` to the number of bytes transferred.
+    pub fn advance(&self, bytes: u64) {
+        if let Some(transfer) = self.status.transfers.write().unwrap().get_mut(&self.id) {
+            transfer.transferred += bytes;
+        }
+    }
+}
+
+impl Drop for TransferProgress {
+    fn drop(&mut self) {
+        self.status.transfers.write().unwrap().remove(&self.id);
+    }
+}
+
+/// Format a number of bytes in a human readable way, e.g `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::status::{format_bytes, Direction, Status};
+
+    #[test]
+    fn test_it_track_transfers_until_dropped() {
+        let status = Status::default();
+        let progress = status.start_transfer(Direction::Upload, "docs/a.txt", Some(10));
+        progress.advance(4);
+        progress.advance(2);
+
+        assert_eq!(status.transfers()[0].transferred, 6);
+
+        drop(progress);
+        assert!(status.transfers().is_empty());
+    }
+
+    #[test]
+    fn test_it_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
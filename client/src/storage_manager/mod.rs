@@ -1,70 +1,250 @@
 use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
 use crate::grpc::upload::{UploadEvent, UploadStatus};
-use anyhow::Result;
-use log::{debug, error, info};
-use reqwest::Client;
-use std::fs::{create_dir_all, File};
-use std::io::copy;
-use std::path::PathBuf;
+use crate::identity::Identity;
+use crate::status::{Direction, Status};
+use anyhow::{anyhow, Result};
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Body, Client};
+use std::fs::{create_dir_all, remove_file, rename, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_util::io::ReaderStream;
 use tonic::transport::Channel;
 
+/// Suffix appended to the temporary file used while a download is in progress.
+const PARTIAL_SUFFIX: &str = ".polydrive.part";
+
+/// Size of the chunks read from disk when streaming an upload.
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum duration without receiving data before a download is abandoned.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The temporary file a download is written to.
+///
+/// It is removed when dropped, unless it was moved into place: when the download fails, and when
+/// it is cancelled, e.g the future is dropped.
+struct PartialFile {
+    path: PathBuf,
+    /// Whether the file was moved into place
+    done: bool,
+}
+
+impl PartialFile {
+    fn new(path: PathBuf) -> Self {
+        Self { path, done: false }
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.done || !self.path.exists() {
+            return;
+        }
+        if let Err(e) = remove_file(&self.path) {
+            warn!(
+                "failed to clean up temporary file. tmp={}, details={}",
+                &self.path.display(),
+                e
+            );
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct StorageManager {
     http_client: Client,
     grpc_client: FileManagerServiceClient<Channel>,
+    /// The identity of the device, sent along with the upload events
+    identity: Identity,
+    /// The status of the daemon, where the progress of the transfers is published
+    status: Status,
 }
 
 impl StorageManager {
     /// Init a reqwest client to make HTTP calls
-    pub fn init(grpc_client: FileManagerServiceClient<Channel>) -> Self {
+    pub fn init(
+        grpc_client: FileManagerServiceClient<Channel>,
+        identity: Identity,
+        status: Status,
+    ) -> Self {
         let http_client = reqwest::Client::new();
         Self {
             http_client,
             grpc_client,
+            identity,
+            status,
         }
     }
 
     /// Upload a file to an URL and notify
     /// the remote server with an `UploadEvent`.
-    pub async fn upload(&self, url: &str, path: &str, _file: File) -> Result<()> {
-        debug!("reading file content in bytes. file={:?}", path);
-        let buffer = std::fs::read(path)?;
-
-        let (status, message): (UploadStatus, Option<String>) =
-            match self.http_client.put(url).body(buffer).send().await {
-                Ok(res) if res.status() == 200 => (UploadStatus::Success, None),
-                Ok(res) => {
-                    error!("Server responded with status code {}", res.status());
-                    (UploadStatus::Failure, Some(res.text().await?))
-                }
-                Err(e) => {
-                    error!("failed to upload file. details = {}", e.to_string());
-                    (UploadStatus::Failure, Some(e.to_string()))
-                }
-            };
+    ///
+    /// An error is returned if the upload failed, once the server has been notified.
+    ///
+    /// The body is streamed from the open `file` in chunks of `UPLOAD_CHUNK_SIZE` bytes, so the
+    /// memory used stays bounded whatever the size of the file.
+    pub async fn upload(&self, url: &str, path: &str, file: File) -> Result<()> {
+        let length = file.metadata()?.len();
+        debug!("streaming file content. file={:?}, length={}", path, length);
+
+        let progress = self
+            .status
+            .start_transfer(Direction::Upload, path, Some(length));
+        let stream =
+            ReaderStream::with_capacity(tokio::fs::File::from_std(file), UPLOAD_CHUNK_SIZE)
+                .inspect(move |chunk| {
+                    if let Ok(chunk) = chunk {
+                        progress.advance(chunk.len() as u64)
+                    }
+                });
+        let request = self
+            .http_client
+            .put(url)
+            .header(CONTENT_LENGTH, length)
+            .body(Body::wrap_stream(stream));
+
+        let (status, message): (UploadStatus, Option<String>) = match request.send().await {
+            Ok(res) if res.status() == 200 => (UploadStatus::Success, None),
+            Ok(res) => {
+                error!("Server responded with status code {}", res.status());
+                (UploadStatus::Failure, Some(res.text().await?))
+            }
+            Err(e) => {
+                error!("failed to upload file. details = {}", e);
+                (UploadStatus::Failure, Some(e.to_string()))
+            }
+        };
 
-        info!("successfully uploaded file {}", path);
         info!("status: {:#?}, message: {:#?}", status, message);
 
         self.notify(UploadEvent {
             path: path.to_string(),
             status: status.into(),
-            message,
+            message: message.clone(),
+            client_name: Some(self.identity.client()),
         })
         .await?;
 
+        if status == UploadStatus::Failure {
+            return Err(anyhow!(
+                "failed to upload file. file={}, details={}",
+                path,
+                message.unwrap_or_default()
+            ));
+        }
+
+        info!("successfully uploaded file {}", path);
         Ok(())
     }
 
-    /// Download a file from minio through a presigned URL
-    #[allow(dead_code)]
-    pub async fn download(&self, url: &str, path: &str) -> Result<()> {
-        let resp = self.http_client.get(url).send().await?.text().await?;
-        let path_buf = PathBuf::from(path);
-        create_dir_all(path_buf.parent().unwrap())?;
-        let mut out = File::create(path).expect("failed to create file");
-        copy(&mut resp.as_bytes(), &mut out).expect("failed to copy content");
-        Ok(())
+    /// Download the file at the remote path `remote` from minio through a presigned URL, into
+    /// the local file at `path`.
+    ///
+    /// The body is streamed in chunks into a temporary file next to the target, which is
+    /// synced to disk and then atomically renamed into place. This way, a reader never sees
+    /// a partially written file, and an existing file is left untouched if the download fails.
+    ///
+    /// The content is checked against its BLAKE3 `hash` before being moved into place, unless
+    /// the hash is empty. The hash of the content downloaded is returned.
+    ///
+    /// The download fails if no data is received for `IDLE_TIMEOUT`, whatever its total duration.
+    pub async fn download(
+        &self,
+        url: &str,
+        remote: &str,
+        path: &str,
+        hash: &str,
+    ) -> Result<String> {
+        let target = PathBuf::from(path);
+        let parent = target
+            .parent()
+            .ok_or_else(|| anyhow!("cannot download to a path without parent. path={}", path))?;
+        create_dir_all(parent)?;
+
+        let mut partial = PartialFile::new(Self::partial_path(&target)?);
+        debug!(
+            "downloading file into temporary file. file={}, tmp={}",
+            path,
+            &partial.path.display()
+        );
+
+        let downloaded = self.download_to(url, &partial.path, remote).await?;
+        if !hash.is_empty() && downloaded != hash {
+            return Err(anyhow!(
+                "downloaded content does not match its hash. file={}, expected={}, actual={}",
+                remote,
+                hash,
+                downloaded
+            ));
+        }
+
+        rename(&partial.path, &target).map_err(|e| {
+            anyhow!(
+                "failed to move downloaded file into place. file={}, details={}",
+                path,
+                e
+            )
+        })?;
+        partial.done = true;
+
+        debug!("successfully downloaded file. file={}", path);
+        Ok(downloaded)
+    }
+
+    /// Stream the response body of `url` into the file at `path`, sync it to disk, and return
+    /// the BLAKE3 hash of its content.
+    ///
+    /// The progress is published under the remote path of the file.
+    async fn download_to(&self, url: &str, path: &Path, remote: &str) -> Result<String> {
+        let mut response = self.http_client.get(url).send().await?.error_for_status()?;
+
+        let mut out = File::create(path).map_err(|e| {
+            anyhow!(
+                "failed to create file. path={}, details={}",
+                path.display(),
+                e
+            )
+        })?;
+
+        let progress =
+            self.status
+                .start_transfer(Direction::Download, remote, response.content_length());
+        let mut hasher = blake3::Hasher::new();
+        while let Some(chunk) = timeout(IDLE_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "download stalled. file={}, timeout={}s",
+                    remote,
+                    IDLE_TIMEOUT.as_secs()
+                )
+            })??
+        {
+            out.write_all(&chunk)?;
+            hasher.update(&chunk);
+            progress.advance(chunk.len() as u64);
+        }
+
+        out.sync_all()?;
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+
+    /// Build the path of the temporary file used to download `target`.
+    fn partial_path(target: &Path) -> Result<PathBuf> {
+        let filename = target
+            .file_name()
+            .ok_or_else(|| anyhow!("invalid file name. path={}", target.display()))?;
+
+        let mut partial = std::ffi::OsString::from(".");
+        partial.push(filename);
+        partial.push(PARTIAL_SUFFIX);
+
+        Ok(target.with_file_name(partial))
     }
 
     /// Notify the remote server with an `UploadEvent`
@@ -77,3 +257,38 @@ impl StorageManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::storage_manager::{PartialFile, StorageManager};
+    use std::fs::write;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_build_partial_path_next_to_target() {
+        let partial = StorageManager::partial_path(&PathBuf::from("/tmp/docs/report.pdf"))
+            .expect("failed to build partial path");
+
+        assert_eq!(
+            partial,
+            PathBuf::from("/tmp/docs/.report.pdf.polydrive.part")
+        );
+    }
+
+    #[test]
+    fn test_it_remove_partial_file_unless_moved_into_place() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = tmp.path().join(".a.txt.polydrive.part");
+
+        write(&path, "partial").expect("failed to write file");
+        drop(PartialFile::new(path.clone()));
+        assert!(!path.exists());
+
+        write(&path, "complete").expect("failed to write file");
+        let mut partial = PartialFile::new(path.clone());
+        partial.done = true;
+        drop(partial);
+        assert!(path.exists());
+    }
+}
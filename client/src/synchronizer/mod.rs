@@ -1,80 +1,562 @@
-use crate::grpc::file::FileRequest;
+use crate::grpc::file::{File, FileEventType, FileRequest};
 use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
 use crate::grpc::server::Notification;
+use crate::identity::Identity;
+use crate::queue::backoff;
+use crate::roots::Roots;
+use crate::state::{Conflict, State};
+use crate::status::{Connection, Status};
 use crate::storage_manager::StorageManager;
+use crate::watcher::WatchHandle;
 use anyhow::Result;
-use log::{debug, info};
-use std::path::PathBuf;
+use chrono::Local;
+use log::{debug, error, info, warn};
+use std::fs::{create_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
 use tonic::transport::Channel;
 use tonic::Streaming;
 
 /// The `Synchronizer` component is responsible to subscribe to
 /// remote server notifications and synchronize the fs with the remote fs.
+#[derive(Clone)]
 pub struct Synchronizer {
     client: FileManagerServiceClient<Channel>,
-    stream: Streaming<Notification>,
+    /// The identity of the device, to ignore the notifications of its own changes
+    identity: Identity,
     storage_manager: StorageManager,
+    state: State,
+    roots: Roots,
+    /// The watched paths, only files matching them are synchronized
+    watches: WatchHandle,
+    /// The status of the daemon, holding the state of the connection
+    status: Status,
 }
 
 impl Synchronizer {
-    /// Bootstrap the synchronizer by opening connection
-    /// to the server stream.
-    pub async fn bootstrap(mut client: FileManagerServiceClient<Channel>) -> Result<Self> {
+    /// Bootstrap the synchronizer.
+    pub async fn bootstrap(
+        client: FileManagerServiceClient<Channel>,
+        identity: Identity,
+        state: State,
+        roots: Roots,
+        watches: WatchHandle,
+        status: Status,
+    ) -> Result<Self> {
         debug!("initializing synchronizer");
 
-        debug!("subscribe to notifications stream");
-        let stream = client.subscribe_notification(()).await?.into_inner();
-        let storage_manager = StorageManager::init(client.clone());
+        let storage_manager =
+            StorageManager::init(client.clone(), identity.clone(), status.clone());
 
         Ok(Self {
-            stream,
             client,
+            identity,
             storage_manager,
+            state,
+            roots,
+            watches,
+            status,
         })
     }
 
-    /// Listen for notifications.
+    /// Subscribe to the server stream and listen for notifications.
+    ///
+    /// The synchronizer subscribes again, with an exponential backoff, whenever the stream
+    /// is lost. Once subscribed again, it catches up with the changes made while it was
+    /// disconnected.
     ///
     /// This is a blocking method.
-    pub async fn listen(&mut self) -> Result<()> {
+    pub async fn listen(&self) -> Result<()> {
         info!("starting synchronizer");
 
-        while let Some(notification) = &self.stream.message().await? {
+        let mut attempts = 0;
+        let mut catch_up = false;
+        loop {
+            debug!("subscribe to notifications stream");
+
+            let error = match self.client.clone().subscribe_notification(()).await {
+                Ok(response) => {
+                    info!("subscribed to notifications stream");
+                    attempts = 0;
+                    self.status.set_connection(Connection::Connected {
+                        since: Local::now(),
+                    });
+
+                    // Notifications received meanwhile are buffered by the stream
+                    if catch_up {
+                        if let Err(e) = self.catch_up().await {
+                            error!(
+                                "an error occurred when catching up with the server. details={}",
+                                e
+                            )
+                        }
+                    }
+                    catch_up = true;
+
+                    match self.consume(response.into_inner()).await {
+                        Ok(()) => String::from("notifications stream closed by the server"),
+                        Err(e) => e.to_string(),
+                    }
+                }
+                Err(e) => e.to_string(),
+            };
+
+            let delay = backoff(attempts);
+            attempts += 1;
+            let now = Local::now();
+            let since = match self.status.connection() {
+                Connection::Disconnected { since, .. } => since,
+                _ => now,
+            };
+            warn!(
+                "lost notifications stream, subscribing again. retry in={}s, details={}",
+                delay.as_secs(),
+                &error
+            );
+            self.status.set_connection(Connection::Disconnected {
+                since,
+                error,
+                retry: now + chrono::Duration::from_std(delay)?,
+            });
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Apply the notifications of the stream, until it ends.
+    async fn consume(&self, mut stream: Streaming<Notification>) -> Result<()> {
+        while let Some(notification) = stream.message().await? {
             debug!("received notification = {:?}", notification);
+            // The changes of this device are already applied
+            if self.identity.is_origin(notification.origin.as_ref()) {
+                debug!("ignoring notification of a local change");
+                continue;
+            }
 
-            if let Some(file) = &notification.file {
-                // At the moment, as we don't have the versioning enabled right now,
-                // we simply check if the file exists on the host, and if not, we request a new
-                // download link in order to download the file.
-                if PathBuf::from(&file.path).exists() {
-                    info!(
-                        "file {} already exists. no synchronization needed.",
-                        &file.path
+            // A notification which cannot be applied must not stop the synchronization
+            // of the next ones.
+            match self.handle(&notification).await {
+                Ok(()) => self.status.record_sync(),
+                Err(e) => {
+                    error!(
+                        "an error occurred when trying to synchronize the file. details={}",
+                        e
                     );
-                    continue;
+                    self.status.record_error(&e.to_string());
                 }
+            }
+        }
 
-                info!("synchronization required due to new file detected that is not present on disk. file={}", &file.path);
+        Ok(())
+    }
 
-                let response = self
-                    .client
-                    .clone()
-                    .file(FileRequest {
-                        client_name: None,
-                        path: file.path.to_string(),
-                        version: Some(1.to_string()),
-                    })
-                    .await?
-                    .into_inner();
+    /// Synchronize every file changed on the server while the stream was lost.
+    async fn catch_up(&self) -> Result<()> {
+        info!("catching up with the changes made while disconnected");
+        let files = self.client.clone().get_files(()).await?.into_inner().data;
+        for file in files {
+            if let Err(e) = self.synchronize(&file).await {
+                error!(
+                    "an error occurred when trying to synchronize the file. file={}, details={}",
+                    &file.path, e
+                );
+                self.status.record_error(&format!("{}: {}", &file.path, e));
+            }
+        }
+        self.status.record_sync();
+        Ok(())
+    }
 
-                self.storage_manager
-                    .download(&response.link, &file.path)
-                    .await?;
+    /// Apply a notification on disk.
+    async fn handle(&self, notification: &Notification) -> Result<()> {
+        if let Some(file) = &notification.file {
+            if notification.event_type() == FileEventType::Move {
+                if let Some(old_path) = &notification.old_path {
+                    if self.replay_move(old_path, file)? {
+                        return Ok(());
+                    }
+                }
+            }
 
-                info!("successfully synchronized file. file={}", &file.path)
+            self.synchronize(file).await?;
+        }
+        Ok(())
+    }
+
+    /// Synchronize a file on disk with the version it was notified about.
+    ///
+    /// Nothing is done if the client already knows this version, or a newer one.
+    pub async fn synchronize(&self, file: &File) -> Result<()> {
+        let version = match file.version {
+            Some(version) => version,
+            None => {
+                warn!(
+                    "notification received without version, ignoring it. file={}",
+                    &file.path
+                );
+                return Ok(());
             }
+        };
+
+        let known = self.state.version(&file.path)?;
+        if version <= known {
+            info!(
+                "file {} already up to date. version={}, known={}",
+                &file.path, version, known
+            );
+            return Ok(());
         }
 
+        let local = match self.roots.to_local(&file.path)? {
+            Some(local) if self.watches.includes(&local) => local,
+            _ => {
+                debug!(
+                    "file is not synchronized on this device. file={}",
+                    &file.path
+                );
+                return Ok(());
+            }
+        };
+
+        if !file.deleted && self.state.has_content(&local, &file.hash) {
+            info!(
+                "file {} already has the content of the remote version. version={}",
+                &file.path, version
+            );
+            return self.state.track(&file.path, version, false, &local);
+        }
+
+        if self.state.is_modified(&file.path, &local)? {
+            self.keep_conflict_copy(&local, version)?;
+        }
+
+        if file.deleted {
+            info!(
+                "file deleted on remote, removing it. file={}, version={}",
+                &file.path, version
+            );
+            if local.exists() {
+                self.watches.expect_change(&local, None);
+                remove_file(&local)?;
+            }
+        } else {
+            info!(
+                "synchronization required due to newer remote version. file={}, version={}, known={}",
+                &file.path, version, known
+            );
+
+            let response = self
+                .client
+                .clone()
+                .file(FileRequest {
+                    client_name: Some(self.identity.client()),
+                    path: file.path.to_string(),
+                    version: Some(version.to_string()),
+                })
+                .await?
+                .into_inner();
+
+            // The hash of the requested version prevails over the one notified
+            let hash = response
+                .file
+                .as_ref()
+                .map_or(file.hash.as_str(), |file| file.hash.as_str());
+            if !hash.is_empty() {
+                self.watches.expect_change(&local, Some(hash));
+            }
+            let downloaded = self
+                .storage_manager
+                .download(
+                    &response.link,
+                    &file.path,
+                    &local.display().to_string(),
+                    hash,
+                )
+                .await?;
+            // The hash may be unknown before the download, for files indexed without one
+            self.watches.expect_change(&local, Some(&downloaded));
+        }
+
+        self.state
+            .track(&file.path, version, file.deleted, &local)?;
+
+        info!(
+            "successfully synchronized file. file={}, local={}",
+            &file.path,
+            &local.display()
+        );
         Ok(())
     }
+
+    /// Keep the local changes made on a file in a conflict copy, before it gets replaced
+    /// by a newer remote version.
+    ///
+    /// The file is copied rather than moved, so the `Indexer` does not see a move, which would
+    /// move the history of the file to the copy. The copy is indexed as a new file, as any other
+    /// created file.
+    fn keep_conflict_copy(&self, local: &Path, version: i32) -> Result<()> {
+        let now = Local::now();
+        let copy = conflict_path(
+            local,
+            &self.identity.host_name,
+            &now.format("%Y-%m-%d").to_string(),
+        );
+
+        warn!(
+            "conflict detected, local changes are kept in a copy. file={}, copy={}, version={}",
+            &local.display(),
+            &copy.display(),
+            version
+        );
+        std::fs::copy(local, &copy)?;
+
+        self.state.add_conflict(Conflict {
+            path: local.display().to_string(),
+            copy: copy.display().to_string(),
+            version,
+            detected: now,
+        })
+    }
+
+    /// Replay on disk a move done on another device, the moved `file` being in its latest version.
+    ///
+    /// Only a local copy holding the content of the moved version is moved, and it never replaces
+    /// another file. Returns `false` if the move cannot be replayed locally, e.g the old file is
+    /// missing or was modified, in which case the file must be downloaded. The local files are
+    /// then left untouched.
+    fn replay_move(&self, old: &str, file: &File) -> Result<bool> {
+        let new = file.path.as_str();
+        let new_path = match self.roots.to_local(new)? {
+            Some(path) if self.watches.includes(&path) => path,
+            // The file is not synchronized on this device
+            _ => return Ok(true),
+        };
+        let version = match file.version {
+            Some(version) => version,
+            None => return Ok(false),
+        };
+        // The old file may be moved only if it holds the moved content
+        let old_path = self
+            .roots
+            .to_local(old)?
+            .filter(|path| self.watches.includes(path))
+            .filter(|path| self.state.has_content(path, &file.hash));
+
+        if self.state.has_content(&new_path, &file.hash) {
+            info!("file {} already moved. no synchronization needed.", new);
+            if let Some(old_path) = old_path {
+                self.watches.expect_change(&old_path, None);
+                remove_file(&old_path)?;
+            }
+            self.state.remove(old)?;
+            self.state.track(new, version, false, &new_path)?;
+            return Ok(true);
+        }
+
+        let old_path = match old_path {
+            Some(path) if !new_path.exists() => path,
+            _ => {
+                debug!(
+                    "cannot replay move, old file is missing or modified, or new file exists. old={}, new={}",
+                    old, new
+                );
+                return Ok(false);
+            }
+        };
+
+        if let Some(parent) = new_path.parent() {
+            create_dir_all(parent)?;
+        }
+        self.watches.expect_change(&old_path, None);
+        self.watches.expect_change(&new_path, Some(&file.hash));
+        rename(&old_path, &new_path)?;
+        // The moved history may be renumbered after the one of the new path
+        self.state.remove(old)?;
+        self.state.track(new, version, false, &new_path)?;
+
+        info!("successfully replayed move. old={}, new={}", old, new);
+        Ok(true)
+    }
+}
+
+/// Build a free path for the conflict copy of `path`, e.g `report (conflict from host 2022-04-01).txt`.
+fn conflict_path(path: &Path, host: &str, date: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut copy = path.with_file_name(format!(
+        "{} (conflict from {} {}){}",
+        stem, host, date, extension
+    ));
+    let mut index = 2;
+    while copy.exists() {
+        copy = path.with_file_name(format!(
+            "{} (conflict from {} {} {}){}",
+            stem, host, date, index, extension
+        ));
+        index += 1;
+    }
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::file::{File as RemoteFile, FileEventType};
+    use crate::grpc::server::Notification;
+    use crate::synchronizer::conflict_path;
+    use crate::test_utils::synchronizer;
+    use std::fs::{read_to_string, write, File};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_build_conflict_path() {
+        assert_eq!(
+            conflict_path(&PathBuf::from("/tmp/report.txt"), "host-b", "2022-04-01"),
+            PathBuf::from("/tmp/report (conflict from host-b 2022-04-01).txt")
+        );
+        assert_eq!(
+            conflict_path(&PathBuf::from("/tmp/Makefile"), "host-b", "2022-04-01"),
+            PathBuf::from("/tmp/Makefile (conflict from host-b 2022-04-01)")
+        );
+    }
+
+    #[test]
+    fn test_it_build_free_conflict_path() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        File::create(tmp.path().join("a (conflict from host-b 2022-04-01).txt"))
+            .expect("failed to create file");
+
+        assert_eq!(
+            conflict_path(&tmp.path().join("a.txt"), "host-b", "2022-04-01"),
+            tmp.path().join("a (conflict from host-b 2022-04-01 2).txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_it_download_moved_file_when_old_file_is_missing() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let synchronizer = synchronizer(tmp.path()).await;
+
+        let notification = Notification {
+            file: Some(RemoteFile {
+                base_name: String::from("b.txt"),
+                path: String::from("docs/b.txt"),
+                version: Some(3),
+                ..RemoteFile::default()
+            }),
+            event_type: FileEventType::Move.into(),
+            old_path: Some(String::from("docs/a.txt")),
+            origin: None,
+        };
+
+        // The move cannot be replayed, so the file is requested from the server
+        let error = synchronizer
+            .handle(&notification)
+            .await
+            .expect_err("the moved file must be downloaded");
+        let status = error
+            .downcast_ref::<tonic::Status>()
+            .expect("the error must come from the server");
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+
+    /// Build the notification of `docs/a.txt` moved to `docs/b.txt`, of content `content`.
+    fn moved(version: i32, content: &str) -> Notification {
+        Notification {
+            file: Some(RemoteFile {
+                base_name: String::from("b.txt"),
+                path: String::from("docs/b.txt"),
+                version: Some(version),
+                hash: blake3::hash(content.as_bytes()).to_hex().to_string(),
+                ..RemoteFile::default()
+            }),
+            event_type: FileEventType::Move.into(),
+            old_path: Some(String::from("docs/a.txt")),
+            origin: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_it_store_version_of_file_moved_onto_existing_history() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let synchronizer = synchronizer(tmp.path()).await;
+        let old = tmp.path().join("docs/a.txt");
+        let new = tmp.path().join("docs/b.txt");
+        write(&old, "report").expect("failed to write file");
+        synchronizer
+            .state
+            .track("docs/a.txt", 1, false, &old)
+            .expect("failed to set state");
+        synchronizer
+            .state
+            .track("docs/b.txt", 4, true, &new)
+            .expect("failed to set state");
+
+        // The server numbered the moved version after the history of the destination
+        synchronizer
+            .handle(&moved(5, "report"))
+            .await
+            .expect("failed to replay move");
+
+        assert!(!old.exists());
+        assert_eq!(read_to_string(&new).unwrap(), "report");
+        assert_eq!(synchronizer.state.get("docs/a.txt").unwrap(), None);
+        let state = synchronizer.state.get("docs/b.txt").unwrap().unwrap();
+        assert_eq!(state.version, 5);
+        assert!(!state.deleted);
+    }
+
+    #[tokio::test]
+    async fn test_it_download_moved_file_when_contents_differ() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let synchronizer = synchronizer(tmp.path()).await;
+        let old = tmp.path().join("docs/a.txt");
+        let new = tmp.path().join("docs/b.txt");
+        write(&old, "report").expect("failed to write file");
+        write(&new, "other report").expect("failed to write file");
+
+        // The destination is not the moved file, so the move cannot be replayed
+        synchronizer
+            .handle(&moved(2, "report"))
+            .await
+            .expect_err("the moved file must be downloaded");
+        assert_eq!(read_to_string(&old).unwrap(), "report");
+        assert_eq!(read_to_string(&new).unwrap(), "other report");
+
+        // Neither can it be when the source is not the moved file
+        write(&old, "edited report").expect("failed to write file");
+        std::fs::remove_file(&new).expect("failed to remove file");
+        synchronizer
+            .handle(&moved(2, "report"))
+            .await
+            .expect_err("the moved file must be downloaded");
+        assert_eq!(read_to_string(&old).unwrap(), "edited report");
+        assert!(!new.exists());
+    }
+
+    #[tokio::test]
+    async fn test_it_keep_conflict_copy_next_to_the_file() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let synchronizer = synchronizer(tmp.path()).await;
+        let local = tmp.path().join("docs/report.txt");
+        write(&local, "local changes").expect("failed to write file");
+
+        synchronizer
+            .keep_conflict_copy(&local, 2)
+            .expect("failed to keep conflict copy");
+
+        // The file stays in place until the remote version replaces it
+        assert_eq!(read_to_string(&local).unwrap(), "local changes");
+        let conflicts = synchronizer
+            .state
+            .conflicts()
+            .expect("failed to list conflicts");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(read_to_string(&conflicts[0].copy).unwrap(), "local changes");
+    }
 }
@@ -0,0 +1,90 @@
+use crate::config::StabilityConfig;
+use crate::grpc::server::file_manager_service_client::FileManagerServiceClient;
+use crate::identity::Identity;
+use crate::ignores::Ignores;
+use crate::indexer::stability::Stability;
+use crate::indexer::Indexer;
+use crate::queue::Queue;
+use crate::roots::Roots;
+use crate::state::State;
+use crate::status::Status;
+use crate::synchronizer::Synchronizer;
+use crate::watcher::{PoolWatcher, WatchHandle};
+use std::collections::BTreeMap;
+use std::fs::create_dir_all;
+use std::path::Path;
+use tonic::transport::{Channel, Endpoint};
+
+/// The components shared by the daemon, for the root `docs` located in a directory.
+struct Components {
+    client: FileManagerServiceClient<Channel>,
+    identity: Identity,
+    state: State,
+    roots: Roots,
+    watches: WatchHandle,
+}
+
+/// Build the components of the root `docs` located in `dir`, with a server which cannot
+/// be reached.
+fn components(dir: &Path) -> Components {
+    let root = dir.join("docs");
+    create_dir_all(&root).expect("failed to create root");
+    let roots = Roots::new(&BTreeMap::from([(String::from("docs"), root.clone())]), &[])
+        .expect("failed to build roots");
+    let ignores = Ignores::new(&[], roots.clone()).expect("failed to build ignores");
+    let watcher = PoolWatcher::init(&[root.display().to_string()], roots.clone(), ignores)
+        .expect("failed to init watcher");
+
+    Components {
+        client: FileManagerServiceClient::new(
+            Endpoint::from_static("http://127.0.0.1:1").connect_lazy(),
+        ),
+        identity: Identity::load(dir).expect("failed to load identity"),
+        state: State::open(&dir.join("state")).expect("failed to open state"),
+        roots,
+        watches: watcher.handle(),
+    }
+}
+
+/// Build a synchronizer from the components.
+async fn bootstrap_synchronizer(components: &Components) -> Synchronizer {
+    Synchronizer::bootstrap(
+        components.client.clone(),
+        components.identity.clone(),
+        components.state.clone(),
+        components.roots.clone(),
+        components.watches.clone(),
+        Status::default(),
+    )
+    .await
+    .expect("failed to bootstrap synchronizer")
+}
+
+/// Build a synchronizer of the root `docs` located in `dir`, with a server which cannot
+/// be reached.
+pub async fn synchronizer(dir: &Path) -> Synchronizer {
+    bootstrap_synchronizer(&components(dir)).await
+}
+
+/// Build an indexer of the root `docs` located in `dir`, with a server which cannot be
+/// reached, along with its queue.
+pub async fn indexer(dir: &Path) -> (Indexer, Queue) {
+    let components = components(dir);
+    let synchronizer = bootstrap_synchronizer(&components).await;
+    let queue = Queue::open(&components.state).expect("failed to open queue");
+
+    let indexer = Indexer::bootstrap(
+        components.client,
+        components.identity,
+        components.state,
+        components.roots,
+        components.watches,
+        queue.clone(),
+        Status::default(),
+        Stability::new(&StabilityConfig::default()),
+        synchronizer,
+    )
+    .await
+    .expect("failed to bootstrap indexer");
+    (indexer, queue)
+}
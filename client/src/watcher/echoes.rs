@@ -0,0 +1,105 @@
+use crate::state::hash;
+use notify::DebouncedEvent;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a change written by the daemon is expected to come back as a watch event.
+///
+/// It must be well over the debounce delay of the watcher.
+const ECHO_TTL: Duration = Duration::from_secs(60);
+
+/// A change written on disk by the daemon itself.
+#[derive(Debug, Clone)]
+struct Echo {
+    /// The hash of the content written, or `None` if the file was removed
+    hash: Option<String>,
+    /// When the change stops being expected
+    expires: Instant,
+}
+
+/// The changes the daemon writes in the watched directories, e.g when synchronizing a
+/// remote version.
+///
+/// The watch events caused by these changes are echoes: they must not be indexed, or the
+/// file would be uploaded again as a new version, and so on between devices.
+#[derive(Debug, Clone, Default)]
+pub struct Echoes {
+    expected: Arc<Mutex<HashMap<PathBuf, Echo>>>,
+}
+
+impl Echoes {
+    /// Register a change about to be written at `path`: a content of hash `hash`, or a
+    /// removal if `hash` is `None`.
+    pub fn expect(&self, path: &Path, hash: Option<&str>) {
+        self.expected.lock().unwrap().insert(
+            path.to_path_buf(),
+            Echo {
+                hash: hash.map(str::to_string),
+                expires: Instant::now() + ECHO_TTL,
+            },
+        );
+    }
+
+    /// Check whether an event was caused by a change registered with `expect`, i.e the
+    /// file is still as the daemon wrote it.
+    pub fn is_echo(&self, event: &DebouncedEvent) -> bool {
+        let mut expected = self.expected.lock().unwrap();
+        let now = Instant::now();
+        expected.retain(|_, echo| echo.expires > now);
+        if expected.is_empty() {
+            return false;
+        }
+
+        let written = |path: &Path| match expected.get(path) {
+            Some(Echo {
+                hash: Some(expected),
+                ..
+            }) => path.is_file() && hash(path).is_ok_and(|hash| &hash == expected),
+            _ => false,
+        };
+        let removed = |path: &Path| {
+            matches!(expected.get(path), Some(Echo { hash: None, .. })) && !path.exists()
+        };
+
+        match event {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => written(path),
+            DebouncedEvent::Remove(path) => removed(path),
+            DebouncedEvent::Rename(old, new) => removed(old) && written(new),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watcher::echoes::Echoes;
+    use notify::DebouncedEvent;
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_it_recognise_changes_written_by_the_daemon() {
+        let tmp = tempdir().expect("failed to create temporary directory");
+        let path = tmp.path().join("a.txt");
+        write(&path, "remote").expect("failed to write file");
+        let hash = crate::state::hash(&path).expect("failed to hash file");
+
+        let echoes = Echoes::default();
+        assert!(!echoes.is_echo(&DebouncedEvent::Create(path.clone())));
+
+        echoes.expect(&path, Some(&hash));
+        assert!(echoes.is_echo(&DebouncedEvent::Create(path.clone())));
+        assert!(echoes.is_echo(&DebouncedEvent::Write(path.clone())));
+
+        // The file was modified since, the change must be indexed
+        write(&path, "local").expect("failed to write file");
+        assert!(!echoes.is_echo(&DebouncedEvent::Write(path.clone())));
+
+        let removed = tmp.path().join("b.txt");
+        echoes.expect(&removed, None);
+        assert!(echoes.is_echo(&DebouncedEvent::Remove(removed.clone())));
+        assert!(!echoes.is_echo(&DebouncedEvent::Remove(path)));
+    }
+}
@@ -1,80 +1,263 @@
+mod echoes;
 mod pool;
 
+use crate::ignores::Ignores;
+use crate::roots::{base_dir, Roots};
+use crate::watcher::echoes::Echoes;
 use crate::watcher::pool::Pool;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use log::{debug, info};
-use notify::{DebouncedEvent, RecursiveMode, Watcher};
-use std::sync::{Arc, Mutex};
+use log::{debug, error, info, warn};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
 #[async_trait]
-pub trait WatcherListener {
+pub trait WatcherListener: Send + Sync {
     /// Function called whenever a file is created
     async fn on_event(&self, event: &DebouncedEvent) -> Result<()>;
 }
 
-#[derive(Clone)]
 pub struct PoolWatcher {
-    /// The pool of files to watch
-    pub(crate) pool: Pool,
+    /// The handle to the watched paths
+    pub(crate) handle: WatchHandle,
+
+    /// The receiver of the file watch events
+    receiver: Receiver<DebouncedEvent>,
 
     /// Listener suscribed to the file watch events
-    pub(crate) listeners: Vec<Arc<Mutex<dyn WatcherListener>>>,
+    pub(crate) listeners: Vec<Arc<dyn WatcherListener>>,
 }
 
 impl PoolWatcher {
     /// Init a `Watcher` instance
-    pub fn init(paths: &[String]) -> Self {
-        let pool = Pool::from(&paths.to_vec());
-        let listeners = vec![];
-        Self { pool, listeners }
-    }
-
-    /// Run the watcher to watch files.
-    pub async fn start(&self) -> Result<()> {
+    pub fn init(paths: &[String], roots: Roots, ignores: Ignores) -> Result<Self> {
         info!("configuring sender and receiver on channel for events");
-        let (tx, rx) = std::sync::mpsc::channel();
+        let (tx, receiver) = std::sync::mpsc::channel();
 
         // TODO: make watch time configurable?
-        let mut watcher = notify::watcher(tx, Duration::from_secs(2))
+        let watcher = notify::watcher(tx, Duration::from_secs(2))
             .map_err(|e| anyhow!("failed to create watcher with error={}", e))?;
 
+        let handle = WatchHandle {
+            pool: Arc::new(RwLock::new(Pool::from(&paths.to_vec()))),
+            watcher: Arc::new(Mutex::new(watcher)),
+            roots,
+            ignores,
+            echoes: Echoes::default(),
+        };
+        let listeners = vec![];
+        Ok(Self {
+            handle,
+            receiver,
+            listeners,
+        })
+    }
+
+    /// Get a handle to change the watched paths while the watcher is running.
+    pub fn handle(&self) -> WatchHandle {
+        self.handle.clone()
+    }
+
+    /// Run the watcher to watch files.
+    pub async fn start(&self) -> Result<()> {
         // Add each path to the watcher
-        for path in &self.pool.paths {
-            debug!("configuring watcher for path={}", &path.display());
-            watcher
-                .watch(path, RecursiveMode::Recursive)
-                .map_err(|e| anyhow!("failed to watch path={}, reason={}", &path.display(), e))?;
+        for path in self.handle.paths() {
+            if !path.exists() {
+                warn!(
+                    "watched path does not exist, ignoring it. path={}",
+                    &path.display()
+                );
+                continue;
+            }
+            self.handle.watch(&path)?;
         }
 
         info!("successfully configured watchers, waiting for events");
 
         loop {
-            match rx.recv() {
-                Ok(event) => self.notify(&event).await?,
+            match self.receiver.recv() {
+                Ok(event) => {
+                    if let Some(event) = self.handle.filter(event) {
+                        self.notify(&event).await
+                    }
+                }
                 Err(e) => println!("received error from channel: {:?}", e),
             }
         }
     }
 
-    pub async fn notify(&self, event: &DebouncedEvent) -> Result<()> {
+    /// Notify the listeners of an event.
+    ///
+    /// A listener failing to handle an event must not stop the watcher, nor the other listeners.
+    pub async fn notify(&self, event: &DebouncedEvent) {
         debug!(
             "notifying {} listeners for event={:?}",
             &self.listeners.len(),
             event
         );
-        for locked_listener in self.listeners.clone() {
-            let listener = locked_listener.lock().unwrap();
+        for listener in self.listeners.clone() {
             // TODO: notify in parallel?
-            listener.on_event(event).await?;
+            if let Err(e) = listener.on_event(event).await {
+                error!(
+                    "an error occurred when handling the event. event={:?}, details={}",
+                    event, e
+                )
+            }
         }
-        Ok(())
     }
 
-    pub fn add_listener(&mut self, listener: Arc<Mutex<dyn WatcherListener>>) -> &mut Self {
+    pub fn add_listener(&mut self, listener: Arc<dyn WatcherListener>) -> &mut Self {
         debug!("adding listener");
         self.listeners.push(listener);
         self
     }
 }
+
+/// `WatchHandle` gives access to the paths watched by a `PoolWatcher`, in order to
+/// add or remove paths without restarting it.
+#[derive(Clone)]
+pub struct WatchHandle {
+    /// The pool of files to watch
+    pool: Arc<RwLock<Pool>>,
+    /// The underlying `notify` watcher
+    watcher: Arc<Mutex<RecommendedWatcher>>,
+    /// The sync roots, updated along with the watched paths
+    roots: Roots,
+    /// The files which must not be synchronized, even if they are watched
+    ignores: Ignores,
+    /// The changes written by the daemon, which must not be synchronized back
+    echoes: Echoes,
+}
+
+impl WatchHandle {
+    /// Get the paths watched by the watcher.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.pool.read().unwrap().paths()
+    }
+
+    /// Get the paths, or glob patterns, the watched paths were expanded from.
+    pub fn patterns(&self) -> Vec<String> {
+        self.pool.read().unwrap().patterns()
+    }
+
+    /// Check whether a path is watched, i.e matches one of the watched paths or patterns
+    /// and is not ignored.
+    pub fn includes(&self, path: &Path) -> bool {
+        self.pool.read().unwrap().matches(path) && !self.ignores.is_ignored(path)
+    }
+
+    /// Register a change the daemon is about to write at `path`: a content of hash `hash`,
+    /// or a removal if `hash` is `None`.
+    ///
+    /// The watch events it causes are then recognised by `is_echo`.
+    pub fn expect_change(&self, path: &Path, hash: Option<&str>) {
+        self.echoes.expect(path, hash)
+    }
+
+    /// Check whether an event was caused by a change the daemon wrote itself.
+    pub fn is_echo(&self, event: &DebouncedEvent) -> bool {
+        self.echoes.is_echo(event)
+    }
+
+    /// Start watching a path, or glob pattern.
+    ///
+    /// The path gets its own sync root if it is not under an existing one.
+    pub fn add(&self, pattern: &str) -> Result<()> {
+        if !base_dir(pattern).exists() {
+            return Err(anyhow!("path does not exist. path={}", pattern));
+        }
+
+        let path = self.pool.write().unwrap().add(pattern)?;
+        if let Err(e) = self.roots.watch(pattern).and_then(|_| self.watch(&path)) {
+            self.pool.write().unwrap().remove(pattern);
+            return Err(e);
+        }
+
+        info!("added path to the watcher. path={}", pattern);
+        Ok(())
+    }
+
+    /// Stop watching a path, or glob pattern.
+    ///
+    /// The sync root of the path is removed if no other watched path needs it, so remote
+    /// changes are no longer written there.
+    pub fn remove(&self, pattern: &str) -> Result<()> {
+        let paths = self
+            .pool
+            .write()
+            .unwrap()
+            .remove(pattern)
+            .ok_or_else(|| anyhow!("path is not watched. path={}", pattern))?;
+
+        for path in paths {
+            debug!("removing watcher for path={}", &path.display());
+            if let Err(e) = self.watcher.lock().unwrap().unwatch(&path) {
+                warn!(
+                    "failed to unwatch path. path={}, details={}",
+                    &path.display(),
+                    e
+                );
+            }
+        }
+
+        let dir = base_dir(pattern);
+        if !self
+            .patterns()
+            .iter()
+            .any(|watched| base_dir(watched).starts_with(&dir))
+        {
+            self.roots.remove(&dir);
+        }
+
+        info!("removed path from the watcher. path={}", pattern);
+        Ok(())
+    }
+
+    /// Filter an event emitted by the `notify` watcher against the watched patterns.
+    ///
+    /// As directories of glob patterns are watched as a whole, events on files which do not
+    /// match any pattern are dropped, along with the events on ignored files. A file renamed to,
+    /// or from, a name which does not match is seen as created, or removed.
+    fn filter(&self, event: DebouncedEvent) -> Option<DebouncedEvent> {
+        let pool = self.pool.read().unwrap();
+        let matches = |path: &Path| pool.matches(path) && !self.ignores.is_ignored(path);
+        let contains = |path: &Path| pool.contains(path) && !self.ignores.is_ignored(path);
+        match event {
+            DebouncedEvent::NoticeWrite(ref path)
+            | DebouncedEvent::NoticeRemove(ref path)
+            | DebouncedEvent::Create(ref path)
+            | DebouncedEvent::Write(ref path)
+            | DebouncedEvent::Chmod(ref path)
+            | DebouncedEvent::Remove(ref path) => {
+                // Directories are kept, as the files under them may match
+                let keep = matches(path) || (path.is_dir() && contains(path));
+                keep.then_some(event)
+            }
+            // The files of a moved directory are filtered by the indexer
+            DebouncedEvent::Rename(ref old, ref new) if new.is_dir() => {
+                let keep = contains(old) || contains(new);
+                keep.then_some(event)
+            }
+            DebouncedEvent::Rename(old, new) => match (matches(&old), matches(&new)) {
+                (true, true) => Some(DebouncedEvent::Rename(old, new)),
+                (false, true) => Some(DebouncedEvent::Create(new)),
+                (true, false) => Some(DebouncedEvent::Remove(old)),
+                (false, false) => None,
+            },
+            _ => Some(event),
+        }
+    }
+
+    /// Add a single path to the `notify` watcher.
+    fn watch(&self, path: &Path) -> Result<()> {
+        debug!("configuring watcher for path={}", &path.display());
+        self.watcher
+            .lock()
+            .unwrap()
+            .watch(path, RecursiveMode::Recursive)
+            .map_err(|e| anyhow!("failed to watch path={}, reason={}", &path.display(), e))
+    }
+}
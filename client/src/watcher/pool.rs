@@ -1,10 +1,131 @@
-use std::path::PathBuf;
+use crate::roots::{base_dir, is_glob};
+use anyhow::{anyhow, Result};
+use glob::{MatchOptions, Pattern};
+use std::path::{Path, PathBuf};
+
+/// Options used to match paths against glob patterns, `*` does not match `/`.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A path, or glob pattern, watched by the pool.
+///
+/// A glob pattern is kept as a match rule: the deepest directory of the pattern which is
+/// not a glob is watched, and its files are filtered against the pattern. That way, files
+/// created after the pattern was added are matched too.
+#[derive(Debug, Clone)]
+pub struct Watch {
+    /// The path or glob pattern, as given by the user
+    pub(crate) pattern: String,
+    /// The path actually watched
+    pub(crate) dir: PathBuf,
+    /// The glob pattern files must match, if any
+    pub(crate) glob: Option<Pattern>,
+}
+
+impl Watch {
+    /// Build a watch from a path, or glob pattern.
+    pub fn new(pattern: &str) -> Result<Self> {
+        if !is_glob(pattern) {
+            return Ok(Self {
+                pattern: pattern.to_string(),
+                dir: PathBuf::from(pattern),
+                glob: None,
+            });
+        }
+
+        let glob = Pattern::new(pattern)
+            .map_err(|e| anyhow!("invalid glob pattern. pattern={}, details={}", pattern, e))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            dir: base_dir(pattern),
+            glob: Some(glob),
+        })
+    }
+
+    /// Check whether a path is matched by the watch.
+    pub fn matches(&self, path: &Path) -> bool {
+        match &self.glob {
+            Some(glob) => glob.matches_path_with(path, MATCH_OPTIONS),
+            None => path.starts_with(&self.dir),
+        }
+    }
+}
 
 /// `Pool` holds data on the file pool the server has to maintain.
 #[derive(Debug, Default, Clone)]
 pub struct Pool {
-    /// `paths` holds a list of all paths
-    pub(crate) paths: Vec<PathBuf>,
+    /// `watches` holds a list of all watched paths
+    pub(crate) watches: Vec<Watch>,
+}
+
+impl Pool {
+    /// Get the paths to watch, i.e the watched paths and the directories of the glob patterns.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::<PathBuf>::new();
+        for watch in &self.watches {
+            if !paths.contains(&watch.dir) {
+                paths.push(watch.dir.clone());
+            }
+        }
+        paths
+    }
+
+    /// Get the patterns of the pool.
+    pub fn patterns(&self) -> Vec<String> {
+        self.watches
+            .iter()
+            .map(|watch| watch.pattern.clone())
+            .collect()
+    }
+
+    /// Check whether a path is matched by any watch of the pool.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.watches.iter().any(|watch| watch.matches(path))
+    }
+
+    /// Check whether a path is under a watched path, whether it matches a pattern or not.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.watches
+            .iter()
+            .any(|watch| path.starts_with(&watch.dir))
+    }
+
+    /// Add a path, or glob pattern, to the pool, and return the path to watch for it.
+    pub fn add(&mut self, pattern: &str) -> Result<PathBuf> {
+        if self.watches.iter().any(|watch| watch.pattern == pattern) {
+            return Err(anyhow!("path is already watched. path={}", pattern));
+        }
+
+        let watch = Watch::new(pattern)?;
+        log::debug!(
+            "adding path to pool. path={}, dir={}",
+            pattern,
+            &watch.dir.display()
+        );
+        let dir = watch.dir.clone();
+        self.watches.push(watch);
+        Ok(dir)
+    }
+
+    /// Remove a path, or glob pattern, from the pool, and return the paths which are
+    /// not watched anymore.
+    ///
+    /// Returns `None` if the pattern was not in the pool.
+    pub fn remove(&mut self, pattern: &str) -> Option<Vec<PathBuf>> {
+        let index = self
+            .watches
+            .iter()
+            .position(|watch| watch.pattern == pattern)?;
+        let removed = self.watches.remove(index);
+
+        if self.paths().contains(&removed.dir) {
+            return Some(vec![]);
+        }
+        Some(vec![removed.dir])
+    }
 }
 
 impl From<&Vec<String>> for Pool {
@@ -19,31 +140,13 @@ impl From<&Vec<String>> for Pool {
 
         log::info!("creating pool from {} path(s).", &files.len());
 
-        let mut paths = Vec::<PathBuf>::new();
-
+        let mut pool = Pool::default();
         for path in files {
-            log::debug!("trying to parse path as glob. path={}", &path);
-
-            if let Ok(glob) = glob::glob(path) {
-                for entry in glob {
-                    match entry {
-                        Ok(file) => {
-                            log::debug!(
-                                "adding path to pool. path={}, glob={}",
-                                &file.display(),
-                                &path
-                            );
-                            paths.push(file)
-                        }
-                        Err(e) => {
-                            log::warn!("failed to decode the glob format with error={}", e)
-                        }
-                    }
-                }
+            if let Err(e) = pool.add(path) {
+                log::warn!("failed to add path to pool. details={}", e)
             }
         }
-
-        Self { paths }
+        pool
     }
 }
 
@@ -60,16 +163,50 @@ mod tests {
         let f1_path = tmp.path().join("test.log");
         let f2_path = tmp.path().join("test.yml");
 
-        File::create(f1_path).expect("Failed to create 'test.log' file");
-        File::create(f2_path).expect("Failed to create 'test.yml' file");
+        File::create(&f1_path).expect("Failed to create 'test.log' file");
+        File::create(&f2_path).expect("Failed to create 'test.yml' file");
 
         let pool = Pool::from(&vec![
             format!("{}/**/*.log", tmp.path().display()),
             format!("{}/**/*.yml", tmp.path().display()),
         ]);
 
-        assert_eq!(pool.paths.len(), 2);
+        assert_eq!(pool.paths(), vec![tmp.path().to_path_buf()]);
+        assert!(pool.matches(&f1_path));
+        assert!(pool.matches(&f2_path));
 
         remove_dir_all(tmp.path()).expect("Failed to clean up test folder")
     }
+
+    #[test]
+    fn test_it_match_files_created_after_startup() {
+        let pool = Pool::from(&vec![
+            String::from("/data/**/*.png"),
+            String::from("/logs/*.log"),
+            String::from("/home/alice/docs"),
+        ]);
+
+        assert!(pool.matches("/data/a.png".as_ref()));
+        assert!(pool.matches("/data/a/b/c.png".as_ref()));
+        assert!(!pool.matches("/data/a/b/c.jpg".as_ref()));
+        assert!(pool.matches("/logs/app.log".as_ref()));
+        assert!(!pool.matches("/logs/old/app.log".as_ref()));
+        assert!(pool.matches("/home/alice/docs/a/b.txt".as_ref()));
+        assert!(pool.contains("/data/a/b/c.jpg".as_ref()));
+    }
+
+    #[test]
+    fn test_it_keep_paths_watched_by_other_patterns_on_remove() {
+        let tmp = tempdir().expect("Failed to create temporary directory");
+        let dir = tmp.path().display().to_string();
+        let mut pool = Pool::from(&vec![dir.clone(), format!("{}/*.log", dir)]);
+
+        assert_eq!(pool.remove(&dir), Some(vec![]));
+        assert_eq!(pool.patterns(), vec![format!("{}/*.log", dir)]);
+        assert_eq!(pool.remove(&dir), None);
+        assert_eq!(
+            pool.remove(&format!("{}/*.log", dir)),
+            Some(vec![tmp.path().to_path_buf()])
+        );
+    }
 }
@@ -11,6 +11,7 @@ import akka.grpc.GrpcServiceException
 import akka.stream.scaladsl
 import akka.stream.scaladsl.{BroadcastHub, Flow, Keep, MergeHub, Sink, Source}
 import com.google.protobuf.empty.Empty
+import com.google.protobuf.timestamp.Timestamp
 import io.grpc.Status
 import io.minio.http.Method
 
@@ -29,19 +30,18 @@ class FileManagerServiceImpl(
   // See more: https://doc.akka.io/docs/akka/current/stream/stream-dynamic.html
   // TODO: add a kill switch
   val (
-    inboundHub: Sink[File, NotUsed],
+    inboundHub: Sink[Notification, NotUsed],
     outboundHub: Source[Notification, NotUsed]
   ) =
     MergeHub
-      .source[File]
-      .map(file => Notification(Option[File](file)))
+      .source[Notification]
       .toMat(BroadcastHub.sink[Notification])(Keep.both)
       // might want to add runWith(Sink.ignore) to not notify
       // when no client available
       .run()
   // We create a flow (sink+source, see definitions) to
   // process, with a backpressure defined
-  val busFlow: Flow[File, Notification, NotUsed] =
+  val busFlow: Flow[Notification, Notification, NotUsed] =
     Flow
       .fromSinkAndSource(inboundHub, outboundHub)
       .backpressureTimeout(3.seconds)
@@ -56,11 +56,32 @@ class FileManagerServiceImpl(
     )
 
     val file = in.getFile
-    val file_doc = FileDocument.from(file)
+    val file_doc = FileDocument.from(file, in.clientName)
+
+    // A change made on top of an outdated version would silently replace the
+    // newer one: the client must synchronize the file first
+    val stale = file.version.exists { base =>
+      (in.eventType == FileEventType.CREATE || in.eventType == FileEventType.UPDATE) &&
+      Await
+        .result(fileRequester.findLatest(file.path), 10.seconds)
+        .exists(latest => !latest.deleted && latest.version.exists(_ > base))
+    }
+    if (stale) {
+      logger.warn(
+        s"rejecting change made on an outdated version. path=${file.path}, base=${file.version}"
+      )
+      return Future.failed(
+        new GrpcServiceException(
+          Status.ABORTED.withDescription(
+            s"conflict: the file has a newer version. path=${file.path}, base=${file.version.get}"
+          )
+        )
+      )
+    }
 
     in.eventType match {
       case FileEventType.CREATE =>
-        fileRequester.findExists(file.path).map {
+        Await.result(fileRequester.findExists(file.path), 10.seconds) match {
           case true  => fileRequester.update(file_doc)
           case false => fileRequester.create(file_doc)
         }
@@ -74,7 +95,19 @@ class FileManagerServiceImpl(
         )
       }
       case FileEventType.DELETE => {
-        fileRequester.delete(file_doc)
+        fileRequester.delete(file_doc).map { _ =>
+          Source
+            .single(
+              Notification(Some(toFile(file_doc)), origin = in.clientName)
+            )
+            .viaMat(busFlow)(Keep.right)
+            .run()
+        }
+      }
+      case FileEventType.MOVE => {
+        return Future.successful(
+          moveFile(in.getOldPath, file, file_doc, in.clientName)
+        )
       }
       case _ => {
         new GrpcServiceException(
@@ -85,12 +118,111 @@ class FileManagerServiceImpl(
       }
     }
 
-    val link = minioClient.getPresignedUrl(file.path, Method.PUT)
+    val link = uploadLink(file_doc)
     Future.successful(
-      FileResponse(link)
+      FileResponse(link, Some(toFile(file_doc)))
+    )
+  }
+
+  /** Convert a document into its `File` representation, with its version.
+    */
+  private def toFile(document: FileDocument): File = {
+    File(
+      baseName = document.base_name,
+      path = document.path,
+      version = document.version,
+      deleted = document.deleted,
+      hash = document.hash.getOrElse(""),
+      lastUpdated = document.last_updated.map(millis =>
+        Timestamp(millis / 1000, ((millis % 1000) * 1000000).toInt)
+      ),
+      size = document.size.getOrElse(0L),
+      device = document.device
     )
   }
 
+  /** The key of the object holding the content of a version.
+    *
+    * Contents are stored by version document, so every version stays available
+    * after the file changed or moved, and a client can only upload the content
+    * of the version it made. Versions indexed without hash, by clients
+    * predating the history, are stored by path.
+    */
+  private def objectKey(document: FileDocument): String = {
+    document.hash.map(_ => s"objects/${document._id}").getOrElse(document.path)
+  }
+
+  /** Generate the link to upload the content of a new version.
+    *
+    * The content of a version never changes once uploaded, so no link is given
+    * for a version whose object already exists.
+    */
+  private def uploadLink(document: FileDocument): String = {
+    val key = objectKey(document)
+    if (document.hash.isDefined && minioClient.pathExists(key)) {
+      throw new GrpcServiceException(
+        Status.ALREADY_EXISTS.withDescription(
+          s"the content of this version is already uploaded. path=${document.path}"
+        )
+      )
+    }
+    minioClient.getPresignedUrl(key, Method.PUT)
+  }
+
+  /** Move the history of a file from `oldPath` to the path of `file`, and notify
+    * clients so they can replay the move.
+    *
+    * If no history exists for `oldPath`, the file is indexed as a new one.
+    * @return
+    *   the moved file in its latest version, along with the link to upload the
+    *   file content, or an empty link if the history was moved
+    */
+  private def moveFile(
+      oldPath: String,
+      file: File,
+      fileDoc: FileDocument,
+      origin: Option[Client]
+  ): FileResponse = {
+    if (!Await.result(fileRequester.findExists(oldPath), 10.seconds)) {
+      logger.info(
+        s"no history found for moved file, indexing it as a new file. old=$oldPath, new=${file.path}"
+      )
+      Await.result(fileRequester.findExists(file.path), 10.seconds) match {
+        case true  => fileRequester.update(fileDoc)
+        case false => fileRequester.create(fileDoc)
+      }
+      return FileResponse(
+        uploadLink(fileDoc),
+        Some(toFile(fileDoc))
+      )
+    }
+
+    val previous = Await.result(fileRequester.findLatest(oldPath), 10.seconds)
+    Await.result(fileRequester.move(oldPath, fileDoc), 10.seconds)
+    // Only the content stored by path must follow the file
+    if (previous.flatMap(_.hash).isEmpty) {
+      minioClient.move(oldPath, file.path)
+    }
+
+    // Clients missing the old file download the moved one, in its latest version
+    val moved = Await
+      .result(fileRequester.findLatest(file.path), 10.seconds)
+      .map(toFile)
+      .getOrElse(File(fileDoc.base_name, file.path))
+    Source
+      .single(
+        Notification(
+          Some(moved),
+          FileEventType.MOVE,
+          Some(oldPath),
+          origin
+        )
+      )
+      .viaMat(busFlow)(Keep.right)
+      .run()
+    FileResponse("", Some(moved))
+  }
+
   override def subscribeNotification(
       in: Empty
   ): scaladsl.Source[Notification, NotUsed] = {
@@ -114,8 +246,12 @@ class FileManagerServiceImpl(
       s"a client requested a file. checking if file exists. path=${in.path}"
     )
 
-    // Check if file exists in DB
-    val fileRequest = fileRequester.findLatest(in.path) map { file =>
+    // Check if file exists in DB, in the requested version if any
+    val document = in.version match {
+      case Some(version) => fileRequester.findVersion(in.path, version.toInt)
+      case None          => fileRequester.findLatest(in.path)
+    }
+    val fileRequest = document map { file =>
       file.getOrElse(
         throw new GrpcServiceException(
           Status.NOT_FOUND.withDescription("File not found in database")
@@ -125,17 +261,18 @@ class FileManagerServiceImpl(
     val file = Await.result(fileRequest, 10.seconds)
 
     // Check if file exists in object storage
-    if (!minioClient.pathExists(in.path)) {
+    val key = objectKey(file)
+    if (!minioClient.pathExists(key)) {
       throw new GrpcServiceException(
         Status.NOT_FOUND.withDescription("File not found in object storage")
       )
     }
 
-    logger.info(s"generating download link for object=${in.path}")
-    val downloadLink = minioClient.getPresignedUrl(in.path, Method.GET)
+    logger.info(s"generating download link for object=$key")
+    val downloadLink = minioClient.getPresignedUrl(key, Method.GET)
 
     Future.successful(
-      FileResponse(downloadLink, Some(File(file.base_name, file.path)))
+      FileResponse(downloadLink, Some(toFile(file)))
     )
   }
 
@@ -152,7 +289,13 @@ class FileManagerServiceImpl(
         logger.info(
           s"File ${event.path} has been successfully uploaded. notifying clients for synchronization"
         )
-        Source.single(File("", event.path)).viaMat(busFlow)(Keep.right).run()
+        fileRequester.findLatest(event.path).map { document =>
+          val file = document.map(toFile).getOrElse(File("", event.path))
+          Source
+            .single(Notification(Some(file), origin = event.clientName))
+            .viaMat(busFlow)(Keep.right)
+            .run()
+        }
       }
       // In case of error, we don't want for now to handle something. We simply log an error
       // and trigger the deletion of the file in the database.
@@ -171,12 +314,23 @@ class FileManagerServiceImpl(
     fileRequester
       .findAll()
       .map(files => {
-        GetFilesResponse(
-          // As we grouping by file name, the _id here is the filename
-          files.map(document =>
-            File(document._id.toString, document.path, document.version)
+        GetFilesResponse(files.map(toFile))
+      })
+  }
+
+  /** Route called by clients to get the history of a file.
+    */
+  override def getVersions(in: GetVersionsRequest): Future[GetVersionsResponse] = {
+    logger.info(s"getting versions of file from DB. path=${in.path}")
+    fileRequester
+      .findVersions(in.path)
+      .map(versions => {
+        if (versions.isEmpty) {
+          throw new GrpcServiceException(
+            Status.NOT_FOUND.withDescription("File not found in database")
           )
-        )
+        }
+        GetVersionsResponse(versions.map(toFile))
       })
   }
 }
@@ -1,6 +1,6 @@
 package fr.dopolytech.polydrive
 
-import grpc.File
+import grpc.{Client, File}
 import persistency.MongoConfig
 
 import akka.event.slf4j.Logger
@@ -14,7 +14,7 @@ import org.mongodb.scala.bson.ObjectId
 import org.mongodb.scala.model.Sorts._
 import org.mongodb.scala.bson.codecs.Macros._
 import org.mongodb.scala.model.{Accumulators, Aggregates}
-import org.mongodb.scala.model.Filters.equal
+import org.mongodb.scala.model.Filters.{and, equal}
 import org.mongodb.scala.model.Updates._
 import org.mongodb.scala.result.{InsertOneResult, UpdateResult}
 import org.mongodb.scala.{MongoClient, MongoCollection, Observable}
@@ -30,16 +30,34 @@ object FileDocument {
       path: String,
       version: Option[Int]
   ): FileDocument = {
-    FileDocument(new ObjectId().toString, base_name, path, None, false)
+    FileDocument(
+      new ObjectId().toString,
+      base_name,
+      path,
+      None,
+      false,
+      None,
+      None,
+      Some(System.currentTimeMillis()),
+      None,
+      None
+    )
   }
 
-  def from(file: File): FileDocument = {
+  /** Build the document of a new version of `file`, made by the client `device`.
+    */
+  def from(file: File, device: Option[Client]): FileDocument = {
     FileDocument(
       new ObjectId().toString,
       base_name = file.baseName,
       path = file.path,
       None,
-      false
+      false,
+      Option(file.hash).filter(_.nonEmpty),
+      Some(file.size),
+      Some(System.currentTimeMillis()),
+      device.map(_.hostName).filter(_.nonEmpty),
+      device.map(_.id).filter(_.nonEmpty)
     )
   }
 }
@@ -48,7 +66,16 @@ case class FileDocument(
     base_name: String,
     path: String,
     var version: Option[Int],
-    var deleted: Boolean
+    var deleted: Boolean,
+    hash: Option[String],
+    // The size of the content, in bytes
+    size: Option[Long],
+    // When the version was made, in milliseconds since UNIX epoch
+    last_updated: Option[Long],
+    // The host name of the client which made the version
+    device: Option[String],
+    // The identifier of the client which made the version
+    device_id: Option[String]
 )
 
 class FileRequester(mongoConfig: MongoConfig) {
@@ -92,6 +119,26 @@ class FileRequester(mongoConfig: MongoConfig) {
     current_coll.insertOne(x).toFuture()
   }
 
+  //move renames every version of the file, so the history is kept.
+  //if the new path already has a history, the moved versions are numbered
+  //after its latest version, so the moved file becomes the latest one
+  def move(oldPath: String, x: FileDocument): Future[UpdateResult] = {
+    logger.info("Performing a move from {} to {}", oldPath, x.path)
+    findLatest(x.path).flatMap { latest =>
+      val offset = latest.flatMap(_.version).getOrElse(0)
+      current_coll
+        .updateMany(
+          equal("path", oldPath),
+          combine(
+            set("path", x.path),
+            set("base_name", x.base_name),
+            inc("version", offset)
+          )
+        )
+        .toFuture()
+    }
+  }
+
   def findExists(path: String): Future[Boolean] = {
     findLatest(path).map {
       case Some(_) => true
@@ -107,6 +154,22 @@ class FileRequester(mongoConfig: MongoConfig) {
       .headOption()
   }
 
+  def findVersion(path: String, version: Int): Future[Option[FileDocument]] = {
+    current_coll
+      .find(and(equal("path", path), equal("version", version)))
+      .first()
+      .headOption()
+  }
+
+  /** Find every version of a file, from the oldest to the newest
+    */
+  def findVersions(path: String): Future[Seq[FileDocument]] = {
+    current_coll
+      .find(equal("path", path))
+      .sort(orderBy(ascending("version")))
+      .toFuture()
+  }
+
   def findLatestVersion(path: String): Option[Int] = {
     var latestVersion: Option[Int] = Some(0)
     val fileRequest = findLatest(path) map { file =>
@@ -125,12 +188,20 @@ class FileRequester(mongoConfig: MongoConfig) {
     current_coll
       .aggregate(
         Seq(
+          // Sort versions first, so the first document of each group is the
+          // latest version of the file
+          Aggregates.sort(descending("version")),
           Aggregates.group(
-            "$base_name",
-            Accumulators.max("version", "$version"),
+            "$path",
+            Accumulators.first("version", "$version"),
             Accumulators.first("path", "$path"),
             Accumulators.first("base_name", "$base_name"),
-            Accumulators.first("deleted", "$deleted")
+            Accumulators.first("deleted", "$deleted"),
+            Accumulators.first("hash", "$hash"),
+            Accumulators.first("size", "$size"),
+            Accumulators.first("last_updated", "$last_updated"),
+            Accumulators.first("device", "$device"),
+            Accumulators.first("device_id", "$device_id")
           )
         )
       )
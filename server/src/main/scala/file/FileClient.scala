@@ -5,9 +5,12 @@ import akka.event.slf4j.Logger
 import io.minio.http.Method
 import io.minio.{
   BucketExistsArgs,
+  CopyObjectArgs,
+  CopySource,
   GetObjectArgs,
   GetPresignedObjectUrlArgs,
   MinioClient,
+  RemoveObjectArgs,
   StatObjectArgs
 }
 
@@ -49,8 +52,9 @@ class FileClient(minioConfig: MinioConfig) {
       client.statObject(args)
       true
     } catch {
+      // A missing object is expected before the content of a version is uploaded
       case e: io.minio.errors.ErrorResponseException => {
-        logger.error(s"${e.getMessage} = $path")
+        logger.debug(s"${e.getMessage} = $path")
         false
       }
     }
@@ -77,4 +81,31 @@ class FileClient(minioConfig: MinioConfig) {
 
     client.getPresignedObjectUrl(args)
   }
+
+  /** Move an object to another path, by copying it and removing the
+    * original one.
+    */
+  def move(from: String, to: String): Unit = {
+    client.copyObject(
+      CopyObjectArgs
+        .builder()
+        .bucket(minioConfig.bucket)
+        .`object`(to)
+        .source(
+          CopySource
+            .builder()
+            .bucket(minioConfig.bucket)
+            .`object`(from)
+            .build()
+        )
+        .build()
+    )
+    client.removeObject(
+      RemoveObjectArgs
+        .builder()
+        .bucket(minioConfig.bucket)
+        .`object`(from)
+        .build()
+    )
+  }
 }